@@ -0,0 +1,117 @@
+//! Benchmarks for buffer text storage on multi-megabyte files.
+//!
+//! Each benchmark is run against both [`TextBuffer`] and a plain `Vec<String>` of lines (the
+//! storage buffers used before), so that the two can be compared directly.
+
+
+
+#[path = "../src/text.rs"]
+#[allow(dead_code)]
+mod text;
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+use text::{Cursor, TextBuffer};
+
+
+
+/// Generates roughly `size` bytes of source-like text.
+fn generate(size: usize) -> String {
+    let mut content = String::with_capacity(size + 64);
+    let mut index = 0;
+    while content.len() < size {
+        content.push_str(&format!("    let value_{index} = compute({index}, \"some text\");\n"));
+        index += 1;
+    }
+
+    content
+}
+
+const SIZES: [usize; 3] = [1 << 20, 4 << 20, 16 << 20];
+
+fn insert_line(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_line");
+    for size in SIZES {
+        let content = generate(size);
+
+        let mut rope = TextBuffer::new(&content);
+        let middle = rope.len_lines() / 2;
+        group.bench_with_input(BenchmarkId::new("rope", size), &middle, |b, &line| {
+            b.iter(|| {
+                let at = Cursor { line, index: 0 };
+                let end = rope.insert(at, "let inserted = true;\n");
+                rope.remove(at, end);
+            })
+        });
+
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        group.bench_with_input(BenchmarkId::new("vec", size), &middle, |b, &line| {
+            b.iter(|| {
+                lines.insert(line, "let inserted = true;".to_string());
+                lines.remove(line);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn join_lines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("join_lines");
+    for size in SIZES {
+        let content = generate(size);
+
+        let mut rope = TextBuffer::new(&content);
+        let line = rope.len_lines() / 2;
+        group.bench_with_input(BenchmarkId::new("rope", size), &line, |b, &line| {
+            b.iter(|| {
+                let start = Cursor { line, index: rope.line_len(line) };
+                rope.remove(start, Cursor { line: line + 1, index: 0 });
+                rope.insert(start, "\n");
+            })
+        });
+
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        group.bench_with_input(BenchmarkId::new("vec", size), &line, |b, &line| {
+            b.iter(|| {
+                let next = lines.remove(line + 1);
+                let split = lines[line].len();
+                lines[line].push_str(&next);
+                let after = lines[line].split_off(split);
+                lines.insert(line + 1, after);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Reads a screenful of lines at the end of the file, as rendering does after scrolling there.
+fn visible_lines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("visible_lines_at_end");
+    for size in SIZES {
+        let content = generate(size);
+
+        let rope = TextBuffer::new(&content);
+        let first = rope.len_lines() - 60;
+        group.bench_with_input(BenchmarkId::new("rope", size), &first, |b, &first| {
+            b.iter(|| {
+                let first = black_box(first);
+                (first..first + 60).map(|line| rope.line_str(line).len()).sum::<usize>()
+            })
+        });
+
+        // Split like the rope, which has an empty last line after the trailing newline.
+        let lines: Vec<String> = content.split('\n').map(String::from).collect();
+        group.bench_with_input(BenchmarkId::new("vec", size), &first, |b, &first| {
+            b.iter(|| {
+                let first = black_box(first);
+                lines[first..first + 60].iter().map(String::len).sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_line, join_lines, visible_lines);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+//! Clipboard access
+
+
+
+/// Somewhere that text can be copied to and pasted from.
+pub trait Clipboard {
+    /// Gets the text on the clipboard, if there is any.
+    fn read(&mut self) -> Option<String>;
+    /// Replaces the content of the clipboard with the given text.
+    fn write(&mut self, text: String);
+}
+
+/// An in-process clipboard, which only holds text copied within the editor.
+#[derive(Default)]
+pub struct Register {
+    text: Option<String>,
+}
+
+impl Clipboard for Register {
+    fn read(&mut self) -> Option<String> {
+        self.text.clone()
+    }
+
+    fn write(&mut self, text: String) {
+        self.text = Some(text);
+    }
+}
+
+/// The operating system's clipboard.
+///
+/// Everything copied is also kept in a [`Register`], which is used whenever the system clipboard
+/// is unavailable, such as when there is no display server.
+pub struct SystemClipboard {
+    system: Option<arboard::Clipboard>,
+    register: Register,
+}
+
+impl SystemClipboard {
+    pub fn new() -> Self {
+        Self {
+            system: arboard::Clipboard::new().ok(),
+            register: Register::default(),
+        }
+    }
+}
+
+impl Clipboard for SystemClipboard {
+    fn read(&mut self) -> Option<String> {
+        if let Some(system) = &mut self.system
+            && let Ok(text) = system.get_text()
+        {
+            return Some(text);
+        }
+
+        self.register.read()
+    }
+
+    fn write(&mut self, text: String) {
+        if let Some(system) = &mut self.system {
+            // The register still holds the text if this fails.
+            let _ = system.set_text(text.as_str());
+        }
+
+        self.register.write(text);
+    }
+}
@@ -0,0 +1,274 @@
+//! Fuzzy file finder
+
+
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{Receiver, TryRecvError}, Arc},
+};
+
+use ignore::{WalkBuilder, WalkState};
+
+
+
+/// How many files are sent from the indexing threads at a time.
+const BATCH_SIZE: usize = 256;
+
+/// Every file under a directory, found by background threads. Ignored and hidden files are
+/// skipped, as they are in a workspace search.
+pub struct FileIndex {
+    root: PathBuf,
+    /// The paths of the files found so far, relative to the root and separated by `/`.
+    files: Vec<String>,
+    results: Receiver<Vec<String>>,
+    cancelled: Arc<AtomicBool>,
+    done: bool,
+}
+
+impl FileIndex {
+    pub fn spawn(root: PathBuf) -> Self {
+        let (sender, results) = std::sync::mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let walk_cancelled = cancelled.clone();
+        let walk_root = root.clone();
+        std::thread::Builder::new()
+            .name("file-index".into())
+            .spawn(move || {
+                WalkBuilder::new(&walk_root)
+                    .require_git(false)
+                    .build_parallel()
+                    .run(|| {
+                        let sender = sender.clone();
+                        let root = walk_root.clone();
+                        let cancelled = walk_cancelled.clone();
+                        let mut batch = Batch { files: vec![], sender };
+                        Box::new(move |entry| {
+                            if cancelled.load(Ordering::Relaxed) {
+                                return WalkState::Quit;
+                            }
+                            let Ok(entry) = entry else { return WalkState::Continue; };
+                            if !entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+                                return WalkState::Continue;
+                            }
+                            if let Ok(path) = entry.path().strip_prefix(&root) {
+                                batch.files.push(relative_path_string(path));
+                            }
+                            if batch.files.len() >= BATCH_SIZE && !batch.send() {
+                                // The index was dropped.
+                                return WalkState::Quit;
+                            }
+
+                            WalkState::Continue
+                        })
+                    });
+            })
+            .expect("failed to spawn file index thread");
+
+        Self {
+            root,
+            files: vec![],
+            results,
+            cancelled,
+            done: false,
+        }
+    }
+
+    /// Adds the files found since this was last called, without blocking. Returns the number of
+    /// files that were added.
+    pub fn poll(&mut self) -> usize {
+        let count = self.files.len();
+        loop {
+            match self.results.try_recv() {
+                Ok(files) => self.files.extend(files),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        self.files.len() - count
+    }
+
+    #[inline]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The paths of the files found so far, relative to the root.
+    #[inline]
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Whether every file has been found.
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl Drop for FileIndex {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The files found by one indexing thread that haven't been sent yet. Whatever is left is sent
+/// when the thread finishes.
+struct Batch {
+    files: Vec<String>,
+    sender: std::sync::mpsc::Sender<Vec<String>>,
+}
+
+impl Batch {
+    /// Sends the files in this batch. Returns false if the index has been dropped.
+    fn send(&mut self) -> bool {
+        self.files.is_empty() || self.sender.send(std::mem::take(&mut self.files)).is_ok()
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+fn relative_path_string(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+
+
+const SCORE_MATCH: i64 = 16;
+/// Matching the first character of a path segment, such as `m` in `src/main.rs`.
+const BONUS_SEGMENT_START: i64 = 12;
+/// Matching the first character of a word within a segment, such as `b` in `foo_bar`.
+const BONUS_WORD_START: i64 = 8;
+/// Matching an uppercase character after a lowercase one, such as `B` in `fooBar`.
+const BONUS_CAMEL_CASE: i64 = 7;
+/// Matching the character after the last one matched.
+const BONUS_CONSECUTIVE: i64 = 5;
+/// Matching a character in the file name, rather than its directories.
+const BONUS_FILE_NAME: i64 = 4;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP_EXTENSION: i64 = 1;
+/// The most recently opened file gets this bonus, and older ones less.
+const BONUS_RECENT: i64 = 48;
+
+/// Scores how well the query matches the given `/` separated path, or returns `None` if the
+/// characters of the query don't appear in the path in order.
+///
+/// Matches score more at the starts of path segments and words, in the file name, and in runs of
+/// consecutive characters, and less for every character skipped between them. The query is
+/// matched case-insensitively unless it has an uppercase character.
+pub fn fuzzy_score(query: &str, path: &str) -> Option<i64> {
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    let fold = |ch: char| if case_sensitive { ch } else { ch.to_lowercase().next().unwrap_or(ch) };
+    let query: Vec<char> = query.chars().map(fold).collect();
+    let chars: Vec<char> = path.chars().collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    // Rule out paths that don't contain the query before doing the more expensive scoring.
+    let mut remaining = query.iter().peekable();
+    for ch in &chars {
+        if remaining.peek().is_some_and(|query_ch| **query_ch == fold(*ch)) {
+            remaining.next();
+        }
+    }
+    if remaining.peek().is_some() {
+        return None;
+    }
+
+    let file_name_start = chars.iter().rposition(|ch| *ch == '/').map_or(0, |index| index + 1);
+    let bonuses: Vec<i64> = (0..chars.len())
+        .map(|index| {
+            let prev = index.checked_sub(1).map(|prev| chars[prev]);
+            let position_bonus = match prev {
+                None | Some('/') => BONUS_SEGMENT_START,
+                Some(prev) if !prev.is_alphanumeric() => BONUS_WORD_START,
+                Some(prev) if prev.is_lowercase() && chars[index].is_uppercase() => BONUS_CAMEL_CASE,
+                _ => 0,
+            };
+            let file_name_bonus = if index >= file_name_start { BONUS_FILE_NAME } else { 0 };
+
+            position_bonus + file_name_bonus
+        })
+        .collect();
+
+    // The best score of the query so far with its last character matched at each position.
+    const NONE: i64 = i64::MIN / 2;
+    let mut prev_row = vec![NONE; chars.len()];
+    let mut row = vec![NONE; chars.len()];
+    for (query_index, query_ch) in query.iter().enumerate() {
+        // The best score of the previous row before a gap that ends at the current position.
+        let mut best_before_gap = NONE;
+        for index in 0..chars.len() {
+            let before = if query_index == 0 {
+                0
+            } else if index == 0 {
+                NONE
+            } else {
+                (prev_row[index - 1] + BONUS_CONSECUTIVE).max(best_before_gap)
+            };
+            row[index] = if fold(chars[index]) == *query_ch && before > NONE {
+                before + SCORE_MATCH + bonuses[index]
+            } else {
+                NONE
+            };
+
+            if index > 0 {
+                best_before_gap = (best_before_gap - PENALTY_GAP_EXTENSION)
+                    .max(prev_row[index - 1] - PENALTY_GAP_START);
+            }
+        }
+        std::mem::swap(&mut prev_row, &mut row);
+    }
+
+    let best = prev_row.into_iter().max().filter(|score| *score > NONE)?;
+    // Prefer shorter paths when the matches are otherwise as good.
+    Some(best - chars.len() as i64 / 8)
+}
+
+/// Ranks the files that match the query, best first. Files are given by their index into
+/// `files`. Files in `recent`, which is ordered from least to most recently opened, are ranked
+/// higher the more recently they were opened.
+pub fn rank_files(query: &str, files: &[String], recent: &[String]) -> Vec<(usize, i64)> {
+    let recency: HashMap<&str, i64> = recent.iter()
+        .rev()
+        .enumerate()
+        .map(|(age, path)| (path.as_str(), BONUS_RECENT / (age as i64 + 1)))
+        .collect();
+
+    let mut ranked: Vec<(usize, i64)> = files.iter()
+        .enumerate()
+        .filter_map(|(index, path)| {
+            let score = fuzzy_score(query, path)?;
+            Some((index, score + recency.get(path.as_str()).copied().unwrap_or(0)))
+        })
+        .collect();
+    ranked.sort_by(|(a_index, a_score), (b_index, b_score)| {
+        b_score.cmp(a_score).then_with(|| files[*a_index].cmp(&files[*b_index]))
+    });
+
+    ranked
+}
+
+/// Gets the given paths relative to the root, in the form used by a [`FileIndex`]. Paths that
+/// aren't under the root are left out.
+pub fn relative_paths(root: &Path, paths: &[PathBuf]) -> Vec<String> {
+    let Ok(root) = root.canonicalize() else { return vec![]; };
+    paths.iter()
+        .filter_map(|path| {
+            let path = path.canonicalize().ok()?;
+            Some(relative_path_string(path.strip_prefix(&root).ok()?))
+        })
+        .collect()
+}
@@ -0,0 +1,344 @@
+//! Git integration
+
+
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, TryRecvError},
+};
+
+use git2::{Oid, Repository, Status, StatusOptions};
+
+
+
+/// How a file differs from the last commit, ordered from least to most important. A directory
+/// has the most important status of the files inside it.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum FileStatus {
+    /// Not tracked, and not ignored.
+    Untracked,
+    /// Added to the index, but not yet committed.
+    Added,
+    /// Changed, removed or renamed, in the index or the working tree.
+    Modified,
+    /// Left with conflicts by a merge.
+    Conflicted,
+}
+
+impl FileStatus {
+    fn from_git(status: Status) -> Option<Self> {
+        if status.is_conflicted() {
+            Some(FileStatus::Conflicted)
+        } else if status.is_index_new() {
+            Some(FileStatus::Added)
+        } else if status.is_wt_new() {
+            Some(FileStatus::Untracked)
+        } else if status.intersects(
+            Status::INDEX_MODIFIED | Status::INDEX_DELETED | Status::INDEX_RENAMED
+                | Status::INDEX_TYPECHANGE | Status::WT_MODIFIED | Status::WT_DELETED
+                | Status::WT_RENAMED | Status::WT_TYPECHANGE,
+        ) {
+            Some(FileStatus::Modified)
+        } else {
+            None
+        }
+    }
+
+    /// The letter shown next to entries with this status.
+    pub fn marker(&self) -> &'static str {
+        match self {
+            FileStatus::Untracked => "U",
+            FileStatus::Added => "A",
+            FileStatus::Modified => "M",
+            FileStatus::Conflicted => "!",
+        }
+    }
+}
+
+/// The status of every file in a workspace that differs from the last commit.
+#[derive(Debug, Default)]
+pub struct RepoStatus {
+    files: HashMap<PathBuf, FileStatus>,
+    dirs: HashMap<PathBuf, FileStatus>,
+}
+
+impl RepoStatus {
+    /// Reads the status of the repository that the workspace at the given path is in. Paths are
+    /// kept relative to the workspace, joined to `root` as it was given, so that they match the
+    /// paths of the workspace's entries.
+    pub fn read(root: &Path) -> Result<Self, git2::Error> {
+        let repo = Repository::discover(root)?;
+        let Some(workdir) = repo.workdir() else {
+            return Err(git2::Error::from_str("repository has no working directory"));
+        };
+        // Where the workspace is in the repository, which is usually at its top.
+        let prefix = match (root.canonicalize(), workdir.canonicalize()) {
+            (Ok(root), Ok(workdir)) => {
+                root.strip_prefix(&workdir).map(Path::to_path_buf).unwrap_or_default()
+            }
+            _ => PathBuf::new(),
+        };
+
+        let mut options = StatusOptions::new();
+        options.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .exclude_submodules(true);
+        let mut status = RepoStatus::default();
+        for entry in repo.statuses(Some(&mut options))?.iter() {
+            let Some(file_status) = FileStatus::from_git(entry.status()) else { continue; };
+            let Some(path) = entry.path() else { continue; };
+            let Ok(relative) = Path::new(path).strip_prefix(&prefix) else { continue; };
+            let path = root.join(relative);
+
+            let dirs = path.ancestors().skip(1);
+            for dir in dirs.take_while(|dir| *dir != root && dir.starts_with(root)) {
+                let dir_status = status.dirs.entry(dir.to_path_buf()).or_insert(file_status);
+                *dir_status = (*dir_status).max(file_status);
+            }
+            status.files.insert(path, file_status);
+        }
+
+        Ok(status)
+    }
+
+    /// The status of the file or directory at the given path, if it differs from the last
+    /// commit.
+    pub fn get(&self, path: &Path) -> Option<FileStatus> {
+        self.files.get(path).or_else(|| self.dirs.get(path)).copied()
+    }
+}
+
+/// A [`RepoStatus`] being read on a background thread, which can take a while in large
+/// repositories.
+pub struct StatusScan {
+    result: Receiver<Result<RepoStatus, git2::Error>>,
+}
+
+impl StatusScan {
+    pub fn spawn(root: PathBuf) -> Self {
+        let (sender, result) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("git-status".into())
+            .spawn(move || {
+                // The scan was dropped if nothing is listening.
+                let _ = sender.send(RepoStatus::read(&root));
+            })
+            .expect("failed to spawn git status thread");
+
+        Self { result }
+    }
+
+    /// Takes the status if it has been read, without blocking.
+    pub fn poll(&self) -> Option<Result<RepoStatus, git2::Error>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(git2::Error::from_str("git status thread stopped")))
+            }
+        }
+    }
+}
+
+/// Reads the content of the file at the given path as of the last commit. Returns `None` if the
+/// file isn't in that commit, or isn't UTF-8.
+pub fn head_content(path: &Path) -> Result<Option<String>, git2::Error> {
+    let Some((repo, relative)) = open_repo_for(path)? else { return Ok(None); };
+
+    let head = match repo.head() {
+        Ok(head) => head,
+        // Nothing has been committed yet.
+        Err(error) if error.code() == git2::ErrorCode::UnbornBranch => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let tree = head.peel_to_tree()?;
+    let entry = match tree.get_path(&relative) {
+        Ok(entry) => entry,
+        Err(error) if error.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let blob = entry.to_object(&repo)?.peel_to_blob()?;
+
+    Ok(std::str::from_utf8(blob.content()).ok().map(str::to_string))
+}
+
+/// The [`head_content`] of a file being read on a background thread, so that the repository's
+/// objects aren't read while drawing.
+pub struct HeadScan {
+    result: Receiver<Result<Option<String>, git2::Error>>,
+}
+
+impl HeadScan {
+    pub fn spawn(path: PathBuf) -> Self {
+        let (sender, result) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("git-head".into())
+            .spawn(move || {
+                // The scan was dropped if nothing is listening.
+                let _ = sender.send(head_content(&path));
+            })
+            .expect("failed to spawn git head thread");
+
+        Self { result }
+    }
+
+    /// Takes the content if it has been read, without blocking.
+    pub fn poll(&self) -> Option<Result<Option<String>, git2::Error>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(git2::Error::from_str("git head thread stopped")))
+            }
+        }
+    }
+}
+
+/// Finds the repository that the file at the given path is in, and the file's path relative to
+/// the repository's working directory. Returns `None` if the file is outside of it.
+fn open_repo_for(path: &Path) -> Result<Option<(Repository, PathBuf)>, git2::Error> {
+    let repo = Repository::discover(path.parent().unwrap_or(path))?;
+    let Some(workdir) = repo.workdir() else { return Ok(None); };
+    let (Ok(path), Ok(workdir)) = (path.canonicalize(), workdir.canonicalize()) else {
+        return Ok(None);
+    };
+    let Ok(relative) = path.strip_prefix(&workdir) else { return Ok(None); };
+    let relative = relative.to_path_buf();
+
+    Ok(Some((repo, relative)))
+}
+
+/// The commit that last changed a line.
+#[derive(Clone, Debug)]
+pub struct LineBlame {
+    /// The first few characters of the commit's id.
+    pub commit: String,
+    pub author: String,
+    /// The commit's date, in the author's time zone.
+    pub date: String,
+    /// The first line of the commit's message.
+    pub summary: String,
+}
+
+impl std::fmt::Display for LineBlame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {} · {} {}", self.author, self.date, self.commit, self.summary)
+    }
+}
+
+/// The commit that last changed each line of a file's text. Lines that have been changed since
+/// the last commit have none.
+#[derive(Debug, Default)]
+pub struct FileBlame {
+    lines: Vec<Option<LineBlame>>,
+}
+
+impl FileBlame {
+    /// Blames each line of the given text, which is the file at the given path as it is being
+    /// edited, on the commits of the repository that the file is in.
+    pub fn read(path: &Path, text: &str) -> Result<Self, git2::Error> {
+        let line_count = text.lines().count();
+        let uncommitted = || Ok(Self { lines: vec![None; line_count] });
+        let Some((repo, relative)) = open_repo_for(path)? else { return uncommitted(); };
+        match repo.head() {
+            Ok(_) => {}
+            // Nothing has been committed yet.
+            Err(error) if error.code() == git2::ErrorCode::UnbornBranch => return uncommitted(),
+            Err(error) => return Err(error),
+        }
+        let file_blame = match repo.blame_file(&relative, None) {
+            Ok(file_blame) => file_blame,
+            // The file hasn't been committed yet.
+            Err(error) if error.code() == git2::ErrorCode::NotFound => return uncommitted(),
+            Err(error) => return Err(error),
+        };
+        let blame = file_blame.blame_buffer(text.as_bytes())?;
+
+        // Lines from the same commit share its details.
+        let mut commits: HashMap<Oid, Option<LineBlame>> = HashMap::new();
+        let mut lines = Vec::with_capacity(line_count);
+        for line_index in 0..line_count {
+            let Some(hunk) = blame.get_line(line_index + 1) else {
+                lines.push(None);
+                continue;
+            };
+            let id = hunk.final_commit_id();
+            let line = commits.entry(id).or_insert_with(|| {
+                // Changed lines are blamed on a commit without an id.
+                let commit = repo.find_commit(id).ok()?;
+                let author = commit.author();
+                let time = author.when();
+                let commit_id = id.to_string();
+
+                Some(LineBlame {
+                    commit: commit_id[..8].to_string(),
+                    author: author.name().unwrap_or("Unknown").to_string(),
+                    date: format_date(time.seconds(), time.offset_minutes()),
+                    summary: commit.summary().unwrap_or_default().to_string(),
+                })
+            });
+            lines.push(line.clone());
+        }
+
+        Ok(Self { lines })
+    }
+
+    /// The commit that last changed the line at the given index, if it has been committed.
+    pub fn line(&self, line_index: usize) -> Option<&LineBlame> {
+        self.lines.get(line_index)?.as_ref()
+    }
+}
+
+/// A [`FileBlame`] being read on a background thread, since blaming a file with a long history
+/// can take a while.
+pub struct BlameScan {
+    result: Receiver<Result<FileBlame, git2::Error>>,
+}
+
+impl BlameScan {
+    pub fn spawn(path: PathBuf, text: String) -> Self {
+        let (sender, result) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("git-blame".into())
+            .spawn(move || {
+                // The scan was dropped if nothing is listening.
+                let _ = sender.send(FileBlame::read(&path, &text));
+            })
+            .expect("failed to spawn git blame thread");
+
+        Self { result }
+    }
+
+    /// Takes the blame if it has been read, without blocking.
+    pub fn poll(&self) -> Option<Result<FileBlame, git2::Error>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(git2::Error::from_str("git blame thread stopped")))
+            }
+        }
+    }
+}
+
+/// Formats a time, given in seconds since the Unix epoch, as a `YYYY-MM-DD` date in the time zone
+/// with the given offset from UTC.
+fn format_date(seconds: i64, offset_minutes: i32) -> String {
+    let days = (seconds + i64::from(offset_minutes) * 60).div_euclid(86_400);
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar, counting years
+    // from March so that leap days fall at the end of them.
+    // See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,157 @@
+//! Workspace search
+
+
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{Receiver, Sender, TryRecvError}, Arc},
+};
+
+use ignore::{WalkBuilder, WalkState};
+
+use crate::Search;
+
+
+
+/// Files larger than this are assumed not to be source code, and aren't searched.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How much of the start of a file is checked for null bytes to decide whether it is binary.
+const BINARY_CHECK_LEN: usize = 8 * 1024;
+
+/// The most characters of a matching line that are kept for its preview.
+const MAX_PREVIEW_LEN: usize = 200;
+
+/// A match of a workspace search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GrepHit {
+    pub path: PathBuf,
+    /// The index of the line the match is on.
+    pub line: usize,
+    /// The column of the start of the match, counted in characters.
+    pub column: usize,
+    /// The byte range of the match in its line.
+    pub range: Range<usize>,
+    /// The line the match is on, without its indentation.
+    pub preview: String,
+}
+
+/// A search of every file in a workspace, run on a pool of background threads.
+///
+/// Files are searched in parallel, skipping any that are ignored by `.gitignore` or `.ignore`
+/// files, hidden, or binary. The search is stopped when this is dropped.
+pub struct WorkspaceGrep {
+    /// The hits in each file that has any, as each file is finished.
+    results: Receiver<Vec<GrepHit>>,
+    cancelled: Arc<AtomicBool>,
+    done: bool,
+}
+
+impl WorkspaceGrep {
+    pub fn spawn(root: PathBuf, search: Search) -> Self {
+        let (sender, results) = std::sync::mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let walk_cancelled = cancelled.clone();
+        std::thread::Builder::new()
+            .name("grep".into())
+            .spawn(move || run_walk(&root, &search, sender, &walk_cancelled))
+            .expect("failed to spawn grep thread");
+
+        Self {
+            results,
+            cancelled,
+            done: false,
+        }
+    }
+
+    /// Takes every hit found so far, without blocking.
+    pub fn poll(&mut self) -> Vec<GrepHit> {
+        let mut hits = vec![];
+        loop {
+            match self.results.try_recv() {
+                Ok(file_hits) => hits.extend(file_hits),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        hits
+    }
+
+    /// Whether every file has been searched, and all of their hits taken.
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl Drop for WorkspaceGrep {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+fn run_walk(root: &Path, search: &Search, sender: Sender<Vec<GrepHit>>, cancelled: &AtomicBool) {
+    WalkBuilder::new(root)
+        // Respect `.gitignore` files even outside of a git repository.
+        .require_git(false)
+        .build_parallel()
+        .run(|| {
+            let sender = sender.clone();
+            Box::new(move |entry| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return WalkState::Quit;
+                }
+                let Ok(entry) = entry else { return WalkState::Continue; };
+                if !entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+                    return WalkState::Continue;
+                }
+
+                let hits = search_file(entry.path(), search);
+                if !hits.is_empty() && sender.send(hits).is_err() {
+                    // The search was dropped.
+                    return WalkState::Quit;
+                }
+
+                WalkState::Continue
+            })
+        });
+}
+
+/// Finds every match in the file at the given path. Files that can't be read, or that look
+/// binary, have no matches.
+fn search_file(path: &Path, search: &Search) -> Vec<GrepHit> {
+    if std::fs::metadata(path).map_or(true, |metadata| metadata.len() > MAX_FILE_SIZE) {
+        return vec![];
+    }
+    let Ok(bytes) = std::fs::read(path) else { return vec![]; };
+    if bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0) {
+        return vec![];
+    }
+    let Ok(content) = std::str::from_utf8(&bytes) else { return vec![]; };
+
+    let mut hits = vec![];
+    for (line_index, line) in content.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        for range in search.find_in_line(line) {
+            let preview = line.trim_start();
+            let preview = match preview.char_indices().nth(MAX_PREVIEW_LEN) {
+                Some((end, _)) => &preview[..end],
+                None => preview,
+            };
+            hits.push(GrepHit {
+                path: path.to_path_buf(),
+                line: line_index,
+                column: line[..range.start].chars().count(),
+                range,
+                preview: preview.to_string(),
+            });
+        }
+    }
+
+    hits
+}
@@ -0,0 +1,598 @@
+//! Syntax highlighting
+
+
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, Sender, TryRecvError},
+};
+
+use serde::Deserialize;
+use syntect::parsing::{ParseScopeError, ParseState, ScopeStack, SyntaxReference, SyntaxSet};
+
+use crate::TextBuffer;
+
+
+
+/// The number of lines the worker parses at a time while it hasn't reached the end of the visible
+/// lines of a buffer.
+const VISIBLE_CHUNK_LINES: usize = 128;
+
+/// The number of lines the worker parses at a time otherwise, before checking for new requests.
+const CHUNK_LINES: usize = 2048;
+
+/// The highlighted scopes of a line, as byte ranges into the line.
+pub type LineScopes = Vec<(Range<usize>, SourceScope)>;
+
+/// A change to the lines of a text buffer: the lines `line..=old_end` were replaced by the lines
+/// `line..=new_end`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LineEdit {
+    pub line: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl LineEdit {
+    /// Applies this edit to a list of per-line values, filling any new lines with `fill`.
+    pub fn splice<T: Clone>(&self, lines: &mut Vec<T>, fill: T) {
+        lines.splice(
+            self.line + 1..self.old_end + 1,
+            std::iter::repeat_n(fill, self.new_end - self.line),
+        );
+    }
+
+    /// Gets the index that the line at the given index was moved to by this edit, or `None` if
+    /// the edit changed or removed it.
+    pub fn map_line(&self, line_index: usize) -> Option<usize> {
+        if line_index < self.line {
+            Some(line_index)
+        } else if line_index > self.old_end {
+            Some(line_index + self.new_end - self.old_end)
+        } else {
+            None
+        }
+    }
+}
+
+
+
+/// Highlights buffers on a background thread, so that parsing large files never blocks
+/// rendering.
+///
+/// Requests carry a snapshot of the buffer's text and the buffer's version, and results are
+/// tagged with the version they were computed from, so that results for stale text can be
+/// discarded.
+pub struct HighlightWorker {
+    requests: Sender<HighlightRequest>,
+    results: Receiver<HighlightResult>,
+}
+
+impl HighlightWorker {
+    pub fn spawn(syntaxes: SyntaxSet, selectors: ScopeSelectors) -> Self {
+        let (requests, request_receiver) = std::sync::mpsc::channel();
+        let (result_sender, results) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("highlight".into())
+            .spawn(move || run_worker(syntaxes, selectors, request_receiver, result_sender))
+            .expect("failed to spawn highlight thread");
+
+        Self {
+            requests,
+            results,
+        }
+    }
+
+    pub fn request(&self, request: HighlightRequest) {
+        // The worker only stops once this sender is dropped.
+        let _ = self.requests.send(request);
+    }
+
+    /// Takes every result the worker has finished so far, without blocking.
+    pub fn results(&self) -> impl Iterator<Item = HighlightResult> + '_ {
+        self.results.try_iter()
+    }
+}
+
+pub struct HighlightRequest {
+    pub buffer_id: u64,
+    pub version: u64,
+    /// Whether to detect the buffer's syntax again and reparse all of it. Otherwise, only the
+    /// lines affected by `edits` are parsed again.
+    pub reset: bool,
+    /// The path used to detect the buffer's syntax.
+    pub path: Option<PathBuf>,
+    /// The edits made since the last request for this buffer.
+    pub edits: Vec<LineEdit>,
+    pub text: TextBuffer,
+    /// The lines that are currently visible, which are parsed first.
+    pub visible: Range<usize>,
+}
+
+pub struct HighlightResult {
+    pub buffer_id: u64,
+    /// The version of the buffer that these scopes were computed for.
+    pub version: u64,
+    pub start_line: usize,
+    /// The scopes of the lines starting at `start_line`.
+    pub scopes: Vec<LineScopes>,
+    /// Whether the worker has finished highlighting this version of the buffer.
+    pub done: bool,
+}
+
+struct WorkerBuffer {
+    highlighter: Option<Highlighter>,
+    text: TextBuffer,
+    version: u64,
+    visible: Range<usize>,
+    /// When this buffer was last requested, so that the most recently requested buffer is
+    /// worked on first.
+    sequence: u64,
+}
+
+impl WorkerBuffer {
+    fn is_dirty(&self) -> bool {
+        self.highlighter.as_ref().is_some_and(Highlighter::is_dirty)
+    }
+}
+
+fn run_worker(
+    syntaxes: SyntaxSet,
+    selectors: ScopeSelectors,
+    requests: Receiver<HighlightRequest>,
+    results: Sender<HighlightResult>,
+) {
+    let mut buffers: HashMap<u64, WorkerBuffer> = HashMap::new();
+    let mut sequence = 0;
+
+    loop {
+        // Only block while there is nothing left to parse.
+        let first = if buffers.values().any(WorkerBuffer::is_dirty) {
+            match requests.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        } else {
+            match requests.recv() {
+                Ok(request) => Some(request),
+                Err(_) => return,
+            }
+        };
+
+        for request in first.into_iter().chain(requests.try_iter()) {
+            sequence += 1;
+            let buffer = buffers.entry(request.buffer_id).or_insert_with(|| WorkerBuffer {
+                highlighter: None,
+                text: request.text.clone(),
+                version: request.version,
+                visible: request.visible.clone(),
+                sequence,
+            });
+            if request.reset {
+                buffer.highlighter = request.path
+                    .and_then(|path| syntaxes.find_syntax_for_file(path).ok().flatten())
+                    .map(|syntax| Highlighter::new(syntax, request.text.len_lines()));
+            } else if let Some(highlighter) = &mut buffer.highlighter {
+                for edit in &request.edits {
+                    highlighter.edit(*edit);
+                }
+            }
+            buffer.text = request.text;
+            buffer.version = request.version;
+            buffer.visible = request.visible;
+            buffer.sequence = sequence;
+
+            if !buffer.is_dirty() {
+                let _ = results.send(HighlightResult {
+                    buffer_id: request.buffer_id,
+                    version: buffer.version,
+                    start_line: 0,
+                    scopes: vec![],
+                    done: true,
+                });
+            }
+        }
+
+        let Some((&buffer_id, buffer)) = buffers.iter_mut()
+            .filter(|(_, buffer)| buffer.is_dirty())
+            .max_by_key(|(_, buffer)| buffer.sequence)
+        else {
+            continue;
+        };
+        let Some(highlighter) = &mut buffer.highlighter else { continue; };
+
+        let max_lines = match highlighter.dirty_start() {
+            Some(start) if start < buffer.visible.end => VISIBLE_CHUNK_LINES,
+            _ => CHUNK_LINES,
+        };
+        if let Some(lines) = highlighter.update(&buffer.text, &syntaxes, &selectors, max_lines) {
+            let result = HighlightResult {
+                buffer_id,
+                version: buffer.version,
+                start_line: lines.start,
+                scopes: lines.map(|line_index| highlighter.line_scopes(line_index).to_vec())
+                    .collect(),
+                done: !highlighter.is_dirty(),
+            };
+            if results.send(result).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+
+
+/// Incrementally highlights the lines of a text buffer.
+///
+/// The parser state at the start of every line is cached, so that after an edit only the lines
+/// from the first edited line onwards need to be parsed again, and only until the parser state
+/// at the start of a line matches the cached state for it.
+pub struct Highlighter {
+    /// The parser state at the start of each line, or `None` if it is not yet known.
+    states: Vec<Option<(ParseState, ScopeStack)>>,
+    /// The highlighted scopes of each line.
+    scopes: Vec<LineScopes>,
+    /// The lines that need to be parsed again. Parsing may continue past the end of this range
+    /// until the parser state converges.
+    dirty: Option<Range<usize>>,
+}
+
+impl Highlighter {
+    pub fn new(syntax: &SyntaxReference, line_count: usize) -> Self {
+        let mut states = vec![None; line_count];
+        states[0] = Some((ParseState::new(syntax), ScopeStack::new()));
+
+        Self {
+            states,
+            scopes: vec![vec![]; line_count],
+            dirty: Some(0..line_count),
+        }
+    }
+
+    /// Whether some lines need to be parsed again.
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// The first line that needs to be parsed again, if any.
+    #[inline]
+    pub fn dirty_start(&self) -> Option<usize> {
+        self.dirty.as_ref().map(|dirty| dirty.start)
+    }
+
+    /// Gets the highlighted scopes of the line at the given index.
+    pub fn line_scopes(&self, line_index: usize) -> &[(Range<usize>, SourceScope)] {
+        self.scopes.get(line_index).map_or(&[], Vec::as_slice)
+    }
+
+    /// Notifies the highlighter that the lines of the text have changed.
+    pub fn edit(&mut self, edit: LineEdit) {
+        edit.splice(&mut self.states, None);
+        edit.splice(&mut self.scopes, vec![]);
+
+        let edited = edit.line..edit.new_end + 1;
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => {
+                // Lines after the edit have moved.
+                let end = if dirty.end > edit.old_end {
+                    dirty.end + edit.new_end - edit.old_end
+                } else {
+                    dirty.end
+                };
+                dirty.start.min(edited.start)..end.max(edited.end)
+            }
+            None => edited,
+        });
+    }
+
+    /// Parses up to `max_lines` of the lines that have changed since the last update, continuing
+    /// until the parser state converges with the cached state. Returns the lines that were
+    /// parsed, if any.
+    pub fn update(
+        &mut self,
+        text: &TextBuffer,
+        syntaxes: &SyntaxSet,
+        selectors: &ScopeSelectors,
+        max_lines: usize,
+    ) -> Option<Range<usize>> {
+        let dirty = self.dirty.take()?;
+        debug_assert_eq!(self.states.len(), text.len_lines());
+
+        let start = dirty.start;
+        let Some((mut parser, mut stack)) = self.states[start].clone() else {
+            // The first dirty line always starts in a known state.
+            unreachable!("unknown parser state for line {start}");
+        };
+
+        let mut line_index = start;
+        let mut finished = false;
+        while line_index - start < max_lines {
+            let line = text.line_str(line_index);
+            let Ok(ops) = parser.parse_line(&line, syntaxes) else {
+                finished = true;
+                break;
+            };
+
+            let scopes = &mut self.scopes[line_index];
+            scopes.clear();
+            for (range, op) in syntect::easy::ScopeRangeIterator::new(&ops, &line) {
+                if stack.apply(op).is_err() {
+                    break;
+                }
+                if range.is_empty() {
+                    continue;
+                }
+                if let Some(scope) = selectors.classify(&stack) {
+                    scopes.push((range, scope));
+                }
+            }
+
+            line_index += 1;
+            if line_index == text.len_lines() {
+                finished = true;
+                break;
+            }
+            let next = (parser.clone(), stack.clone());
+            if line_index >= dirty.end && self.states[line_index].as_ref() == Some(&next) {
+                // Every line after this one will be parsed the same as it was before.
+                finished = true;
+                break;
+            }
+            self.states[line_index] = Some(next);
+        }
+
+        if !finished {
+            self.dirty = Some(line_index..dirty.end.max(line_index + 1));
+        }
+
+        Some(start..line_index)
+    }
+}
+
+
+
+/// Classifies scope stacks as [`SourceScope`]s, using a list of rules that each map a scope
+/// selector to the scope that matching text is highlighted as.
+///
+/// When several rules match, the one whose selector matches most specifically wins, and ties go
+/// to the rule that comes last.
+#[derive(Clone, Debug)]
+pub struct ScopeSelectors {
+    rules: Vec<(syntect::highlighting::ScopeSelectors, SourceScope)>,
+}
+
+impl ScopeSelectors {
+    /// Gets the scope that text with the given scope stack should be highlighted as, if any.
+    pub fn classify(&self, stack: &ScopeStack) -> Option<SourceScope> {
+        let scopes = stack.as_slice();
+        let mut best = None;
+        for (selector, scope) in &self.rules {
+            let Some(power) = selector.does_match(scopes) else { continue; };
+            if best.is_none_or(|(best_power, _)| power >= best_power) {
+                best = Some((power, *scope));
+            }
+        }
+
+        best.map(|(_, scope)| scope)
+    }
+
+    /// Adds a rule that highlights text matching the given selector as the given scope. It takes
+    /// precedence over existing rules that match equally well.
+    pub fn add_rule(&mut self, selector: &str, scope: SourceScope) -> Result<(), ParseScopeError> {
+        self.rules.push((selector.parse()?, scope));
+        Ok(())
+    }
+
+    /// Loads the default rules, followed by any rules in the given TOML file.
+    ///
+    /// ```toml
+    /// [[rule]]
+    /// selector = "variable.other.constant, support.constant"
+    /// scope = "constant"
+    /// ```
+    pub fn from_toml(path: &Path) -> Result<Self, SelectorsError> {
+        let content = std::fs::read_to_string(path).map_err(|error| SelectorsError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let file: SelectorsFile = toml::from_str(&content).map_err(|error| SelectorsError::Toml {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let mut selectors = Self::default();
+        for rule in file.rule {
+            let scope = SourceScope::from_name(&rule.scope).ok_or_else(|| {
+                SelectorsError::UnknownScope {
+                    path: path.to_path_buf(),
+                    name: rule.scope.clone(),
+                }
+            })?;
+            selectors.add_rule(&rule.selector, scope).map_err(|error| {
+                SelectorsError::InvalidSelector {
+                    path: path.to_path_buf(),
+                    selector: rule.selector.clone(),
+                    error,
+                }
+            })?;
+        }
+
+        Ok(selectors)
+    }
+}
+
+impl Default for ScopeSelectors {
+    fn default() -> ScopeSelectors {
+        let mut selectors = ScopeSelectors { rules: vec![] };
+        for (selector, scope) in [
+            ("comment - comment.block.attribute", SourceScope::Comment),
+            ("comment.line.documentation, comment.block.documentation", SourceScope::DocComment),
+            ("entity.name.function, support.function", SourceScope::Function),
+            ("keyword, storage", SourceScope::Keyword),
+            (
+                "entity.name.class, entity.name.struct, entity.name.enum, entity.name.type",
+                SourceScope::Type,
+            ),
+            ("string, punctuation.definition.string", SourceScope::String),
+            ("constant.numeric", SourceScope::Number),
+            ("support.macro, entity.name.macro", SourceScope::Macro),
+            ("storage.modifier.lifetime, entity.name.lifetime", SourceScope::Lifetime),
+            ("meta.annotation, meta.attribute, comment.block.attribute", SourceScope::Attribute),
+            (
+                "constant.language, constant.character, constant.other, variable.other.constant",
+                SourceScope::Constant,
+            ),
+            ("keyword.operator", SourceScope::Operator),
+        ] {
+            selectors.add_rule(selector, scope).unwrap();
+        }
+
+        selectors
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SelectorsFile {
+    #[serde(default)]
+    rule: Vec<RuleEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    selector: String,
+    scope: String,
+}
+
+#[derive(Debug)]
+pub enum SelectorsError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Toml {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    InvalidSelector {
+        path: PathBuf,
+        selector: String,
+        error: ParseScopeError,
+    },
+    UnknownScope {
+        path: PathBuf,
+        name: String,
+    },
+}
+
+impl std::fmt::Display for SelectorsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectorsError::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            SelectorsError::Toml { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            SelectorsError::InvalidSelector { path, selector, error } => {
+                write!(f, "{}: invalid selector {:?}: {}", path.display(), selector, error)
+            }
+            SelectorsError::UnknownScope { path, name } => {
+                write!(f, "{}: unknown scope `{}`", path.display(), name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SelectorsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SelectorsError::Io { error, .. } => Some(error),
+            SelectorsError::Toml { error, .. } => Some(error),
+            SelectorsError::InvalidSelector { error, .. } => Some(error),
+            SelectorsError::UnknownScope { .. } => None,
+        }
+    }
+}
+
+
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SourceScope {
+    Comment,
+    DocComment,
+    Function,
+    Keyword,
+    Type,
+    String,
+    Number,
+    Macro,
+    Lifetime,
+    Attribute,
+    Constant,
+    Operator,
+}
+
+impl SourceScope {
+    pub const ALL: [SourceScope; 12] = [
+        SourceScope::Comment,
+        SourceScope::DocComment,
+        SourceScope::Function,
+        SourceScope::Keyword,
+        SourceScope::Type,
+        SourceScope::String,
+        SourceScope::Number,
+        SourceScope::Macro,
+        SourceScope::Lifetime,
+        SourceScope::Attribute,
+        SourceScope::Constant,
+        SourceScope::Operator,
+    ];
+
+    /// The name of the scope in theme and selector files.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            SourceScope::Comment => "comment",
+            SourceScope::DocComment => "doc_comment",
+            SourceScope::Function => "function",
+            SourceScope::Keyword => "keyword",
+            SourceScope::Type => "type",
+            SourceScope::String => "string",
+            SourceScope::Number => "number",
+            SourceScope::Macro => "macro",
+            SourceScope::Lifetime => "lifetime",
+            SourceScope::Attribute => "attribute",
+            SourceScope::Constant => "constant",
+            SourceScope::Operator => "operator",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.name() == name)
+    }
+
+    /// A TextMate scope that this scope is classified from, used to pick its color out of
+    /// `.tmTheme` files.
+    pub const fn representative_scope(&self) -> &'static str {
+        match self {
+            SourceScope::Comment => "comment.line",
+            SourceScope::DocComment => "comment.line.documentation",
+            SourceScope::Function => "entity.name.function",
+            SourceScope::Keyword => "keyword.control",
+            SourceScope::Type => "entity.name.type",
+            SourceScope::String => "string.quoted.double",
+            SourceScope::Number => "constant.numeric",
+            SourceScope::Macro => "support.macro",
+            SourceScope::Lifetime => "storage.modifier.lifetime",
+            SourceScope::Attribute => "meta.annotation",
+            SourceScope::Constant => "constant.language",
+            SourceScope::Operator => "keyword.operator",
+        }
+    }
+}
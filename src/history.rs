@@ -0,0 +1,222 @@
+//! Undo history
+
+
+
+use std::collections::VecDeque;
+
+use crate::{Cursor, Selection};
+
+
+
+/// The default number of bytes the history may use before it starts forgetting its oldest
+/// transactions.
+pub const DEFAULT_BUDGET: usize = 8 * 1024 * 1024;
+
+/// A record of the edits made to a buffer, grouped into undoable transactions.
+pub struct History {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    /// The transaction currently being recorded, if any.
+    pending: Option<Transaction>,
+    /// Whether the next character insertion may be merged into the last undo transaction.
+    can_coalesce: bool,
+    size: usize,
+    budget: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::with_budget(DEFAULT_BUDGET)
+    }
+}
+
+impl History {
+    pub fn with_budget(budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            pending: None,
+            can_coalesce: false,
+            size: 0,
+            budget,
+        }
+    }
+
+    /// Starts a new transaction. Every edit recorded until [`History::commit`] is called will be
+    /// undone and redone as a single step.
+    pub fn begin(&mut self, cursor: Cursor, selection: Selection) {
+        if self.pending.is_none() {
+            self.pending = Some(Transaction {
+                edits: vec![],
+                cursor_before: cursor,
+                selection_before: selection,
+                cursor_after: cursor,
+            });
+        }
+    }
+
+    /// Records an edit, adding it to the pending transaction if there is one.
+    pub fn record(&mut self, edit: Edit) {
+        if let Some(pending) = &mut self.pending {
+            pending.edits.push(edit);
+        } else {
+            let (cursor_before, cursor_after) = match &edit {
+                Edit::Insert { start, end, .. } => (*start, *end),
+                Edit::Delete { start, end, .. } => (*end, *start),
+            };
+            self.push(Transaction {
+                edits: vec![edit],
+                cursor_before,
+                selection_before: Selection::None,
+                cursor_after,
+            });
+            self.can_coalesce = false;
+        }
+    }
+
+    /// Finishes the pending transaction. Transactions that made no edits are discarded.
+    pub fn commit(&mut self, cursor: Cursor) {
+        let Some(mut transaction) = self.pending.take() else { return; };
+        if transaction.edits.is_empty() {
+            // Nothing was edited, so the cursor may have moved.
+            self.can_coalesce = false;
+            return;
+        }
+        transaction.cursor_after = cursor;
+
+        let is_char_insert = transaction.is_char_insert();
+        if is_char_insert
+            && self.can_coalesce
+            && let Some(last) = self.undo.back_mut()
+            && last.coalesce(&transaction)
+        {
+            self.size += transaction.edits[0].text().len();
+            self.redo.clear();
+            self.enforce_budget();
+            return;
+        }
+
+        self.push(transaction);
+        self.can_coalesce = is_char_insert;
+    }
+
+    /// Prevents the next character insertion from being merged into the last transaction.
+    ///
+    /// This should be called whenever the cursor moves without an edit being made.
+    pub fn seal(&mut self) {
+        self.can_coalesce = false;
+    }
+
+    /// Removes the last transaction from the undo stack and moves it to the redo stack. The
+    /// caller is responsible for applying the transaction's inverse to the buffer.
+    pub fn undo(&mut self) -> Option<&Transaction> {
+        let transaction = self.undo.pop_back()?;
+        self.can_coalesce = false;
+        self.redo.push(transaction);
+        self.redo.last()
+    }
+
+    /// Removes the last transaction from the redo stack and moves it to the undo stack. The
+    /// caller is responsible for re-applying the transaction to the buffer.
+    pub fn redo(&mut self) -> Option<&Transaction> {
+        let transaction = self.redo.pop()?;
+        self.can_coalesce = false;
+        self.undo.push_back(transaction);
+        self.undo.back()
+    }
+
+    fn push(&mut self, transaction: Transaction) {
+        self.size += transaction.size();
+        for discarded in self.redo.drain(..) {
+            self.size -= discarded.size();
+        }
+        self.undo.push_back(transaction);
+        self.enforce_budget();
+    }
+
+    fn enforce_budget(&mut self) {
+        // Always keep the latest transaction, even if it is over budget on its own.
+        while self.size > self.budget && self.undo.len() > 1 {
+            if let Some(oldest) = self.undo.pop_front() {
+                self.size -= oldest.size();
+            }
+        }
+    }
+}
+
+
+
+/// A group of edits that are undone and redone together.
+pub struct Transaction {
+    /// The edits in the order that they were made.
+    pub edits: Vec<Edit>,
+    pub cursor_before: Cursor,
+    pub selection_before: Selection,
+    pub cursor_after: Cursor,
+}
+
+impl Transaction {
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.edits.iter().map(|edit| edit.size()).sum::<usize>()
+    }
+
+    fn is_char_insert(&self) -> bool {
+        match self.edits.as_slice() {
+            [Edit::Insert { text, .. }] => {
+                let mut chars = text.chars();
+                chars.next().is_some_and(|ch| ch != '\n') && chars.next().is_none()
+            }
+            _ => false,
+        }
+    }
+
+    /// Merges a single character insertion into this transaction if it directly follows the
+    /// last insertion made by this transaction.
+    fn coalesce(&mut self, next: &Transaction) -> bool {
+        let (
+            Some(Edit::Insert { end, text, .. }),
+            [Edit::Insert { start: next_start, end: next_end, text: next_text }],
+        ) = (self.edits.last_mut(), next.edits.as_slice()) else {
+            return false;
+        };
+        if end != next_start {
+            return false;
+        }
+
+        text.push_str(next_text);
+        *end = *next_end;
+        self.cursor_after = next.cursor_after;
+
+        true
+    }
+}
+
+/// A single change made to a buffer's text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Edit {
+    /// `text` was inserted at `start`, leaving the end of the inserted text at `end`.
+    Insert {
+        start: Cursor,
+        end: Cursor,
+        text: String,
+    },
+    /// `text` was removed from between `start` and `end`.
+    Delete {
+        start: Cursor,
+        end: Cursor,
+        text: String,
+    },
+}
+
+impl Edit {
+    pub fn text(&self) -> &str {
+        match self {
+            Edit::Insert { text, .. } | Edit::Delete { text, .. } => text,
+        }
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.text().len()
+    }
+}
@@ -2,13 +2,34 @@
 
 
 
+mod clipboard;
+mod finder;
+mod git;
+mod grep;
+mod highlight;
+mod history;
+mod search;
+mod text;
+mod theme;
+mod vcs;
+mod watch;
 mod workspace;
 
-use std::{collections::HashSet, ops::Range, path::PathBuf};
+use std::{borrow::Cow, collections::HashSet, ops::Range, path::{Path, PathBuf}};
 
 use bog::{prelude::*, render::FontFamily};
 use unicode_segmentation::UnicodeSegmentation as _;
 
+use clipboard::*;
+use finder::*;
+use git::*;
+use grep::*;
+use highlight::*;
+use history::{Edit, History};
+use search::*;
+use text::*;
+use theme::*;
+use watch::*;
 use workspace::*;
 
 
@@ -28,16 +49,78 @@ pub const GRAY_9: Color = Color::new(191, 191, 197, 255); // bfbfc5
 fn main() -> Result<()> {
     let workspace_info = find_workspace();
     println!("WORKSPACE_DIR: {}", workspace_info.path.display());
-    println!("HAS_VERSION_CONTROL: {}", workspace_info.has_vc);
-    let workspace = read_workspace(workspace_info)?;
+    match workspace_info.vcs {
+        Some(vcs) => println!("VERSION_CONTROL: {vcs}"),
+        None => println!("VERSION_CONTROL: none"),
+    }
+    let mut workspace = read_workspace(workspace_info)?;
+    let expanded = load_expanded(&workspace.info);
+    workspace.restore_expanded(&expanded);
+    let watcher = FileWatcher::new(&workspace.info.path)
+        .inspect_err(|error| eprintln!("ERROR: {error}"))
+        .ok();
+    let status_scan = workspace.info.is_git()
+        .then(|| StatusScan::spawn(workspace.info.path.clone()));
+
+    let selectors = match config_dir().map(|dir| dir.join("selectors.toml")) {
+        Some(path) if path.exists() => ScopeSelectors::from_toml(&path).unwrap_or_else(|error| {
+            eprintln!("ERROR: {error}");
+            ScopeSelectors::default()
+        }),
+        _ => ScopeSelectors::default(),
+    };
+    let highlights = HighlightWorker::spawn(
+        syntect::parsing::SyntaxSet::load_defaults_nonewlines(),
+        selectors,
+    );
+
+    let mut themes = vec![Theme::default()];
+    if let Some(dir) = themes_dir() {
+        let (user_themes, errors) = load_themes(&dir);
+        for error in errors {
+            eprintln!("ERROR: {error}");
+        }
+        themes.extend(user_themes);
+    }
 
-    let syntaxes = syntect::parsing::SyntaxSet::load_defaults_nonewlines();
+    let mut buffers = BufferSet::new();
+    for path in std::env::args_os().skip(1).map(PathBuf::from) {
+        if let Err(error) = buffers.open(&path) {
+            eprintln!("ERROR: {error}");
+        }
+    }
 
     run_app(App {
         cell_size: vec2(1.0, 1.0), // Cannot be 0.
         workspace,
-        buffers: BufferSet::new("./src/main.rs".into(), include_str!("main.rs")),
-        syntaxes,
+        watcher,
+        watch_due: None,
+        conflicts: vec![],
+        git_status: RepoStatus::default(),
+        status_scan,
+        status_stale: false,
+        buffers,
+        highlights,
+        themes,
+        theme: 0,
+        clipboard: SystemClipboard::new(),
+        files_area: Rect::default(),
+        gutter_area: Rect::default(),
+        buffer_area: Rect::default(),
+        mouse_pos: vec2(0.0, 0.0),
+        dragging: false,
+        last_click: None,
+        wheel_remainder: 0.0,
+        search_prompt: None,
+        grep: None,
+        head_diff: None,
+        show_blame: false,
+        blame: None,
+        picker: None,
+        file_prompt: None,
+        message: None,
+        files_focused: false,
+        files_scroll: 0,
         keys_down: HashSet::with_capacity(3),
     })?;
 
@@ -50,609 +133,3056 @@ struct App {
     cell_size: Vec2,
 
     workspace: Workspace,
+    /// Watches the workspace for changes made outside of the editor, if it could be watched.
+    watcher: Option<FileWatcher>,
+    /// When the watcher is next checked for changes, if the window is only being redrawn to
+    /// check it.
+    watch_due: Option<std::time::Instant>,
+    /// The buffers whose files changed while they had unsaved edits, oldest first. The first is
+    /// shown until it is resolved.
+    conflicts: Vec<FileConflict>,
+    /// How the workspace's files differ from the last commit, as of the last status scan.
+    git_status: RepoStatus,
+    /// The status scan that is running, if any.
+    status_scan: Option<StatusScan>,
+    /// Whether files changed while the status was being scanned, so it needs scanning again.
+    status_stale: bool,
     buffers: BufferSet,
-    syntaxes: syntect::parsing::SyntaxSet,
+    highlights: HighlightWorker,
+    /// The loaded themes. The first is always the default theme.
+    themes: Vec<Theme>,
+    /// The index of the current theme.
+    theme: usize,
+    clipboard: SystemClipboard,
+    /// Where the file tree, gutter and buffer were last drawn, for finding what the mouse is over.
+    files_area: Rect,
+    gutter_area: Rect,
+    buffer_area: Rect,
+    mouse_pos: Vec2,
+    /// Whether the primary mouse button was pressed over the buffer, and is still held.
+    dragging: bool,
+    /// When and where the last click in the buffer was, and how many clicks in a row it
+    /// completed.
+    last_click: Option<(std::time::Instant, (u16, u16), u8)>,
+    /// Wheel movement that hasn't added up to a whole line yet, in pixels.
+    wheel_remainder: f32,
+    /// The search being typed, if the search prompt is open.
+    search_prompt: Option<SearchPrompt>,
+    /// The last workspace search, whose hits are listed in a results buffer.
+    grep: Option<GrepResults>,
+    /// The last diff of a buffer against the last commit, which is shown in its own buffer.
+    head_diff: Option<HeadDiff>,
+    /// Whether the commit that last changed the cursor's line is shown after it.
+    show_blame: bool,
+    /// The blame of the current buffer, or the blame being read for it.
+    blame: Option<BufferBlame>,
+    /// The file picker, if it is open.
+    picker: Option<FilePicker>,
+    /// The file operation being named or confirmed in the file tree, if any.
+    file_prompt: Option<FilePrompt>,
+    /// An error to show below the buffer until the next key is pressed.
+    message: Option<String>,
+    /// Whether keys navigate the file tree, rather than editing the buffer.
+    files_focused: bool,
+    /// The index of the first visible entry in the file tree.
+    files_scroll: usize,
     keys_down: HashSet<KeyCode>,
 }
 
-impl AppHandler for App {
-    fn startup(&mut self, cx: AppContext) {
-        // cx.renderer.load_font(include_bytes!("../data/JetBrainsMonoNerdFont_Regular.ttf"));
-        cx.renderer.set_monospace_family("JetBrainsMono Nerd Font");
+struct SearchPrompt {
+    query: String,
+    options: SearchOptions,
+    /// Where the cursor was when the prompt was opened. Matches are searched for from here as
+    /// the query is typed.
+    origin: Cursor,
+    /// Why the query couldn't be compiled, if it couldn't.
+    error: Option<String>,
+    /// What matches are replaced with, if the prompt replaces matches as well as finding them.
+    replace: Option<Replace>,
+    /// Whether typing edits the replacement rather than the query.
+    editing_replace: bool,
+    /// Only replace the matches inside the selections.
+    in_selection: bool,
+    /// The first few lines that replacing every match would change.
+    preview: Vec<ReplacePreview>,
+    /// The buffer version that the preview was made for, or `None` if it needs to be made again.
+    preview_version: Option<u64>,
+    /// How many matches the last replace-all replaced.
+    replaced: Option<usize>,
+    /// Whether this searches every file in the workspace, rather than the current buffer.
+    workspace: bool,
+}
 
-        self.cell_size = cx.renderer.measure_text(&Text {
-            content: "█".into(),
-            size: 17.0,
-            font_family: FontFamily::Monospace,
-            ..Default::default()
-        });
+/// The height of each entry in the file tree.
+const ENTRY_HEIGHT: f32 = 17.0;
+
+/// The width of the space for git status markers at the end of each entry in the file tree.
+const STATUS_MARKER_WIDTH: f32 = 11.0;
+
+/// The width of the line change markers at the edge of the gutter.
+const CHANGE_MARKER_WIDTH: f32 = 3.0;
+
+/// The most matches that the file picker shows at once.
+const MAX_PICKER_ROWS: usize = 10;
+
+struct FilePicker {
+    query: String,
+    index: FileIndex,
+    /// The paths of the recently opened files in the workspace, relative to it.
+    recent: Vec<String>,
+    /// The files that match the query, best first, as indices into the index's files.
+    matches: Vec<usize>,
+    /// The index into `matches` of the selected file.
+    selected: usize,
+}
+
+impl FilePicker {
+    fn update_matches(&mut self) {
+        self.matches = rank_files(&self.query, self.index.files(), &self.recent).into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        self.selected = self.selected.min(self.matches.len().saturating_sub(1));
     }
+}
 
-    fn render<'pass>(&'pass mut self, cx: AppContext, layers: &mut LayerStack<'pass>) {
-        let buffer = self.buffers.current_buffer_mut();
-        if buffer.needs_reparse {
-            // buffer.parse(&self.syntaxes);
-            buffer.needs_reparse = false;
-        }
+/// The most lines of a conflict's diff that are shown.
+const MAX_CONFLICT_ROWS: usize = 10;
 
-        layers.start_layer(cx.renderer.viewport_rect());
-        layers.fill_quad(Quad {
-            bounds: cx.renderer.viewport_rect(),
-            bg_color: GRAY_1,
-            ..Default::default()
-        });
-        layers.end_layer();
-        layers.start_layer(cx.renderer.viewport_rect());
+struct FileConflict {
+    buffer_id: u64,
+    path: PathBuf,
+    /// The file's new content.
+    disk: String,
+    /// The changes from the buffer's text to the file's, as a unified diff.
+    diff: Vec<String>,
+}
 
-        let (side_area, buffer_area) = cx.renderer.viewport_rect().hsplit_portion(0.2);
+struct FilePrompt {
+    op: FileOp,
+    /// The path typed for the new or renamed entry, relative to the workspace.
+    input: String,
+}
 
-        let (header_area, files_area) = side_area.vsplit_len(37.0);
-        let header_area = header_area.shrink(7.0, 7.0);
-        let files_area = files_area.shrink_h(11.0);
+enum FileOp {
+    NewFile,
+    NewDir,
+    /// Renames or moves the entry at the path.
+    Rename(PathBuf),
+    /// Moves the entry at the path to the trash, once confirmed.
+    Delete(PathBuf),
+    /// Writes the current buffer to the path, which becomes its file.
+    SaveAs,
+}
 
-        let header_text = self.workspace.info.path.file_name()
-            .and_then(|os_str| os_str.to_str())
-            .unwrap();
+struct GrepResults {
+    grep: WorkspaceGrep,
+    /// The buffer that the hits are listed in, one per line after a header line.
+    buffer_id: u64,
+    hits: Vec<GrepHit>,
+    file_count: usize,
+}
 
-        layers.fill_text(Text {
-            content: header_text.into(),
-            color: GRAY_7,
-            size: 13.0,
-            bounds: header_area,
-            ..Default::default()
-        });
+/// The number of columns between the end of the cursor's line and its blame.
+const BLAME_GAP_COLUMNS: usize = 4;
 
-        let mut y_offset = 0.0;
-        for entry in self.workspace.entries() {
-            if entry.level > 0 {
-                let padding = 7.0 * entry.level as f32;
-                layers.fill_text(Text {
-                    content: entry.name.into(),
-                    color: GRAY_5,
-                    size: 11.0,
-                    bounds: Rect::new(
-                        vec2(files_area.x + padding, files_area.y + y_offset),
-                        vec2(files_area.w - padding, 17.0),
-                    ),
-                    ..Default::default()
-                });
-            } else {
-                layers.fill_text(Text {
-                    content: entry.name.into(),
-                    color: GRAY_5,
-                    size: 11.0,
-                    bounds: Rect::new(
-                        vec2(files_area.x, files_area.y + y_offset),
-                        vec2(files_area.w, 17.0),
-                    ),
-                    ..Default::default()
-                });
-            }
-            y_offset += 17.0;
-        }
+/// The number of unchanged lines shown around each hunk of a diff against the last commit.
+const DIFF_CONTEXT_LINES: usize = 3;
 
-        let (gutter_area, buffer_area) = buffer_area.hsplit_len(73.0);
+/// How often the watcher is checked for changes while nothing else needs the window redrawn.
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
-        let buffer_cols = (buffer_area.w / self.cell_size.x).floor() as usize;
-        let buffer_rows = (buffer_area.h / self.cell_size.y).floor() as usize;
-        buffer.cols = buffer_cols;
-        buffer.rows = buffer_rows;
+/// How long a buffer has to go without edits before its changes since the last commit are found
+/// again for the gutter.
+const HUNK_DELAY: std::time::Duration = std::time::Duration::from_millis(300);
 
-        let mut cursor_row = 0;
-        let mut last_line_index = 1;
-        let selection = buffer.selection_bounds();
-        let mut y_offset = 0.0;
-        for (index, row) in buffer.visible_rows().enumerate() {
-            if row.line_index != last_line_index {
-                layers.fill_text(Text {
-                    content: format!("{}", row.line_index + 1).into(),
-                    color: if row.line_index == buffer.cursor.line { GRAY_6 } else { GRAY_5 },
-                    size: 17.0,
-                    bounds: Rect::new(
-                        vec2(gutter_area.x, gutter_area.y + y_offset),
-                        gutter_area.size(),
-                    ),
-                    font_family: FontFamily::Monospace,
-                    ..Default::default()
-                });
-            }
+struct HeadDiff {
+    /// The buffer that the diff is shown in, one line of it per line after a header line.
+    buffer_id: u64,
+    /// The buffer that was compared with the last commit.
+    source_id: u64,
+    /// The line of the compared buffer that each line of the diff is at.
+    source_lines: Vec<usize>,
+}
 
-            // Highlight selection.
-            if let Some((start_cursor, end_cursor)) = &selection {
-                if row.line_index >= start_cursor.line && row.line_index <= end_cursor.line {
-                    let start_pos = self.cell_size.x * (start_cursor.index % buffer_cols) as f32;
-                    let end_pos = self.cell_size.x * (end_cursor.index % buffer_cols) as f32;
+struct BufferBlame {
+    buffer_id: u64,
+    /// The version of the buffer that was blamed.
+    version: u64,
+    /// The blame being read, if it hasn't been yet.
+    scan: Option<BlameScan>,
+    blame: Option<FileBlame>,
+}
 
-                    if start_cursor.line == end_cursor.line {
-                        // Highlight from the start position to the end position.
-                        layers.fill_quad(Quad {
-                            bounds: Rect::new(
-                                vec2(buffer_area.x + start_pos, buffer_area.y + y_offset),
-                                vec2(end_pos - start_pos, self.cell_size.y),
-                            ),
-                            bg_color: GRAY_3,
-                            ..Default::default()
-                        });
-                    } else if row.line_index == start_cursor.line {
-                        // Highlight from the start position to the end of the row.
-                        let width = self.cell_size.x * row.content.len() as f32;
-                        layers.fill_quad(Quad {
-                            bounds: Rect::new(
-                                vec2(buffer_area.x + start_pos, buffer_area.y + y_offset),
-                                vec2(width - start_pos, self.cell_size.y),
-                            ),
-                            bg_color: GRAY_3,
-                            ..Default::default()
-                        });
-                    } else if row.line_index > start_cursor.line
-                        && row.line_index < end_cursor.line
-                    {
-                        // Highlight the whole line.
-                        let width = self.cell_size.x * row.content.len() as f32;
-                        layers.fill_quad(Quad {
-                            bounds: Rect::new(
-                                vec2(buffer_area.x, buffer_area.y + y_offset),
-                                vec2(width, self.cell_size.y),
-                            ),
-                            bg_color: GRAY_3,
-                            ..Default::default()
-                        });
-                    } else if row.line_index == end_cursor.line {
-                        // Highlight from the start of the row to the end position.
-                        layers.fill_quad(Quad {
-                            bounds: Rect::new(
-                                vec2(buffer_area.x, buffer_area.y + y_offset),
-                                vec2(end_pos, self.cell_size.y),
-                            ),
-                            bg_color: GRAY_3,
-                            ..Default::default()
-                        });
-                    }
-                }
-            }
+impl App {
+    fn perform_action(&mut self, action: EditAction) {
+        self.buffers.current_buffer_mut().perform_action(action, &mut self.clipboard);
+    }
 
-            layers.fill_text(Text {
-                content: row.content.into(),
-                color: GRAY_7,
-                size: 17.0,
-                bounds: Rect::new(
-                    vec2(buffer_area.x, buffer_area.y + y_offset),
-                    buffer_area.size(),
-                ),
-                font_family: FontFamily::Monospace,
-                ..Default::default()
-            });
-            last_line_index = row.line_index;
-            if row.line_index == buffer.cursor.line {
-                if row.index * buffer_cols <= buffer.cursor.index {
-                    cursor_row = index;
-                }
-            }
+    /// Gets the column and row of the buffer's text at the given position. Positions outside the
+    /// buffer are clamped to its edges.
+    fn cell_at(&self, pos: Vec2) -> (u16, u16) {
+        // Round the column, so that clicking on the right half of a character puts the cursor
+        // after it.
+        let col = ((pos.x - self.buffer_area.x) / self.cell_size.x).round().max(0.0);
+        let row = ((pos.y - self.buffer_area.y) / self.cell_size.y).floor().max(0.0);
 
-            y_offset += self.cell_size.y;
-        }
+        (col as u16, row as u16)
+    }
 
-        // Draw cursor.
-        layers.fill_quad(Quad {
-            bounds: Rect::new(
-                vec2(
-                    buffer_area.x
-                        + (self.cell_size.x * (buffer.cursor.index % buffer_cols) as f32)
-                        - 1.0, // Offset here so it is exactly centered.
-                    buffer_area.y + (self.cell_size.y * cursor_row as f32),
-                ),
-                vec2(2.0, self.cell_size.y),
-            ),
-            bg_color: GRAY_9,
-            ..Default::default()
-        });
+    /// Selects and opens the file tree entry at the given position.
+    fn click_entry(&mut self, pos: Vec2) {
+        let index = ((pos.y - self.files_area.y) / ENTRY_HEIGHT).floor();
+        if index < 0.0 {
+            return;
+        }
+        let Some(entry) = self.workspace.entries().nth(self.files_scroll + index as usize) else {
+            return;
+        };
+        let path = entry.path.to_path_buf();
+        self.workspace.selected = Some(path.clone());
+        self.open_entry(&path);
+    }
 
-        layers.end_layer();
+    /// Opens the file at the given path, or expands or collapses the directory there. Opening a
+    /// file moves focus to it, and directories take focus so that they can be navigated.
+    fn open_entry(&mut self, path: &Path) {
+        if path.is_dir() {
+            self.files_focused = true;
+            if let Err(error) = self.workspace.toggle(path) {
+                self.show_error(format!("{}: {error}", path.display()));
+            }
+            if let Err(error) = save_expanded(&self.workspace) {
+                self.show_error(error);
+            }
+        } else {
+            self.files_focused = false;
+            if let Err(error) = self.buffers.open(path) {
+                self.show_error(error);
+            }
+        }
     }
 
-    // fn on_primary_mouse_down(&mut self, cx: AppContext) {
-    //     let buffer = self.buffers.current_buffer_mut();
-    //     buffer.perform_action(EditAction::Click((
-    //         self.mouse_pos.x.saturating_sub(buffer.area.x),
-    //         self.mouse_pos.y.saturating_sub(buffer.area.y),
-    //     )));
-    // }
+    /// Shows an error below the buffer until the next key is pressed.
+    fn show_error(&mut self, error: impl std::fmt::Display) {
+        self.message = Some(error.to_string());
+    }
 
-    fn on_key_down(&mut self, cx: AppContext, code: KeyCode, _repeat: bool) {
-        let _is_repeat = self.keys_down.insert(code);
+    /// Opens a prompt for the given file operation, with the path of the selected entry typed in
+    /// for it. New entries start in the selected directory, or the one the selected file is in.
+    fn open_file_prompt(&mut self, op: FileOp) {
+        let current_path = self.buffers.current_buffer().path();
+        let input = match &op {
+            FileOp::SaveAs if current_path.is_some() => {
+                current_path.map(|path| self.workspace.relative_name(path)).unwrap_or_default()
+            }
+            FileOp::NewFile | FileOp::NewDir | FileOp::SaveAs => {
+                let dir = self.workspace.selected.as_deref()
+                    .and_then(|path| if path.is_dir() { Some(path) } else { path.parent() })
+                    .map(|dir| self.workspace.relative_name(dir))
+                    .unwrap_or_default();
+                if dir.is_empty() { dir } else { dir + "/" }
+            }
+            FileOp::Rename(path) => self.workspace.relative_name(path),
+            FileOp::Delete(_) => String::new(),
+        };
+        self.file_prompt = Some(FilePrompt { op, input });
+    }
 
-        cx.window.request_redraw();
+    /// Handles a key press while a file prompt is open. Returns whether the key was used.
+    fn file_prompt_key_down(&mut self, code: KeyCode) -> bool {
+        let Some(prompt) = &mut self.file_prompt else { return false; };
+        if self.keys_down.contains(&KeyCode::C_LCTRL) {
+            return false;
+        }
+        if let FileOp::Delete(_) = prompt.op {
+            // Anything but `y` cancels.
+            if let Some(prompt) = self.file_prompt.take()
+                && util::keycode_to_char(code) == Some('y')
+            {
+                self.run_file_op(prompt);
+            }
+            return true;
+        }
         match code {
-            KeyCode::C_ARROWLEFT => {
-                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
-                    self.buffers.current_buffer_mut().start_or_continue_selection();
-                }
-                if self.keys_down.contains(&KeyCode::C_LCTRL) {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::MovePrevWord);
-                } else {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::MoveLeft);
+            KeyCode::C_ESCAPE => {
+                self.file_prompt = None;
+            }
+            KeyCode::C_ENTER => {
+                if let Some(prompt) = self.file_prompt.take() {
+                    self.run_file_op(prompt);
                 }
             }
-            KeyCode::C_ARROWRIGHT => {
-                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
-                    self.buffers.current_buffer_mut().start_or_continue_selection();
+            KeyCode::C_BACKSPACE => {
+                prompt.input.pop();
+            }
+            KeyCode::C_SPACE => {
+                prompt.input.push(' ');
+            }
+            other => {
+                let Some(ch) = util::keycode_to_char(other) else { return false; };
+                let shift = self.keys_down.contains(&KeyCode::C_LSHIFT);
+                prompt.input.push(if shift { util::shifted_char(ch) } else { ch });
+            }
+        }
+
+        true
+    }
+
+    /// Performs a file operation from a file prompt, showing any error it fails with.
+    fn run_file_op(&mut self, prompt: FilePrompt) {
+        let result = match prompt.op {
+            FileOp::NewFile => self.workspace.path_for(&prompt.input).and_then(|path| {
+                self.workspace.create_file(&path)?;
+                self.workspace.reveal(&path);
+                self.workspace.selected = Some(path.clone());
+                self.open_entry(&path);
+                Ok(())
+            }),
+            FileOp::NewDir => self.workspace.path_for(&prompt.input).and_then(|path| {
+                self.workspace.create_dir(&path)?;
+                self.workspace.reveal(&path);
+                self.workspace.selected = Some(path);
+                Ok(())
+            }),
+            FileOp::Rename(from) => self.workspace.path_for(&prompt.input).and_then(|to| {
+                // Open files are found before they are moved, while their paths still exist.
+                let moved = self.buffers.files_under(&from);
+                self.workspace.rename(&from, &to)?;
+                for (id, relative) in moved {
+                    // Joining an empty path would add a trailing separator.
+                    let path = if relative.as_os_str().is_empty() {
+                        to.clone()
+                    } else {
+                        to.join(relative)
+                    };
+                    self.buffers.move_file(id, path);
                 }
-                if self.keys_down.contains(&KeyCode::C_LCTRL) {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::MoveNextWord);
-                } else {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::MoveRight);
+                self.workspace.reveal(&to);
+                Ok(())
+            }),
+            FileOp::Delete(path) => self.workspace.delete(&path),
+            FileOp::SaveAs => self.workspace.path_for(&prompt.input).map(|path| {
+                match self.buffers.current_buffer_mut().save_as(path.clone()) {
+                    Ok(()) => {
+                        self.workspace.refresh_ancestors(&path);
+                        self.workspace.reveal(&path);
+                    }
+                    Err(error) => self.show_error(error),
                 }
+            }),
+        };
+        if let Err(error) = result {
+            self.show_error(error);
+        }
+        if let Err(error) = save_expanded(&self.workspace) {
+            self.show_error(error);
+        }
+    }
+
+    /// Handles a key press while the file tree has focus. Returns whether the key was used.
+    fn files_key_down(&mut self, code: KeyCode) -> bool {
+        if !self.files_focused {
+            return false;
+        }
+        let selected = self.workspace.selected.clone();
+        let selected_dir = selected.as_ref().filter(|path| path.is_dir());
+        let expanded = self.workspace.entries()
+            .any(|entry| entry.expanded && Some(entry.path) == selected.as_deref());
+        match code {
+            KeyCode::C_ESCAPE => {
+                self.files_focused = false;
             }
             KeyCode::C_ARROWUP => {
-                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
-                    self.buffers.current_buffer_mut().start_or_continue_selection();
-                }
-                self.buffers.current_buffer_mut().perform_action(EditAction::MoveUp);
+                self.workspace.move_selection(-1);
             }
             KeyCode::C_ARROWDOWN => {
-                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
-                    self.buffers.current_buffer_mut().start_or_continue_selection();
-                }
-                self.buffers.current_buffer_mut().perform_action(EditAction::MoveDown);
+                self.workspace.move_selection(1);
             }
-
-            KeyCode::C_BACKSPACE => {
-                self.buffers.current_buffer_mut().perform_action(EditAction::Backspace);
+            // Right expands a directory, then moves into it.
+            KeyCode::C_ARROWRIGHT => match selected_dir {
+                Some(path) if !expanded => self.open_entry(&path.clone()),
+                Some(_) => self.workspace.move_selection(1),
+                None => {}
+            },
+            // Left collapses a directory, then moves out to its parent.
+            KeyCode::C_ARROWLEFT => match selected_dir {
+                Some(path) if expanded => self.open_entry(&path.clone()),
+                _ => self.workspace.select_parent(),
+            },
+            KeyCode::C_ENTER | KeyCode::C_SPACE => {
+                if let Some(path) = selected {
+                    self.open_entry(&path);
+                }
             }
             KeyCode::C_DELETE => {
-                self.buffers.current_buffer_mut().perform_action(EditAction::Delete);
-            }
-
-            KeyCode::C_SPACE => {
-                self.buffers.current_buffer_mut().perform_action(EditAction::Insert(' '));
-            }
-            // TODO: Indentation.
-            KeyCode::C_TAB => {
-                self.buffers.current_buffer_mut().perform_action(EditAction::Insert('\t'));
-            }
-            KeyCode::C_ENTER => {
-                self.buffers.current_buffer_mut().perform_action(EditAction::NewLine);
+                if let Some(path) = selected {
+                    self.open_file_prompt(FileOp::Delete(path));
+                }
             }
-
-            other => {
-                if let Some(ch) = util::keycode_to_char(other) {
-                    if self.keys_down.contains(&KeyCode::C_LCTRL) {
-                        match ch {
-                            '[' => {
-                                self.buffers.goto_previous(true);
-                            }
-                            ']' => {
-                                self.buffers.goto_next(true);
-                            }
-                            _ => {}
+            other if !self.keys_down.contains(&KeyCode::C_LCTRL) => {
+                match util::keycode_to_char(other) {
+                    Some('n') if self.keys_down.contains(&KeyCode::C_LSHIFT) => {
+                        self.open_file_prompt(FileOp::NewDir);
+                    }
+                    Some('n') => self.open_file_prompt(FileOp::NewFile),
+                    Some('r') => {
+                        if let Some(path) = selected {
+                            self.open_file_prompt(FileOp::Rename(path));
                         }
-                    } else if self.keys_down.contains(&KeyCode::C_LSHIFT) {
-                        let ch = util::shifted_char(ch);
-                        self.buffers.current_buffer_mut().perform_action(EditAction::Insert(ch));
-                    } else {
-                        self.buffers.current_buffer_mut().perform_action(EditAction::Insert(ch));
                     }
+                    Some('d') => {
+                        if let Some(path) = selected {
+                            self.open_file_prompt(FileOp::Delete(path));
+                        }
+                    }
+                    Some('.') => {
+                        self.workspace.show_ignored = !self.workspace.show_ignored;
+                    }
+                    // Nothing is typed into the buffer.
+                    _ => {}
                 }
             }
+            // Shortcuts still work.
+            _ => return false,
         }
-    }
 
-    fn on_key_up(&mut self, _cx: AppContext, code: KeyCode) {
-        let _was_present = self.keys_down.remove(&code);
+        true
     }
 
-    fn on_wheel_movement(&mut self, _cx: AppContext, movement: WheelMovement) {
-        match movement {
-            WheelMovement::Lines { y, .. } => {
-                if y.is_sign_negative() {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::ScrollDown);
-                } else {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::ScrollUp);
+    /// Brings the file tree and open buffers up to date with the changes seen by the watcher.
+    ///
+    /// Clean buffers are reloaded, while dirty ones are queued as conflicts for the user to
+    /// resolve.
+    fn apply_file_changes(&mut self) {
+        let Some(watcher) = &self.watcher else { return; };
+        let changes = watcher.changes();
+        if changes.paths.is_empty() && !changes.rescan {
+            return;
+        }
+
+        let mut errors = vec![];
+        if changes.rescan {
+            if let Err(error) = self.workspace.refresh_all() {
+                errors.push(error.to_string());
+            }
+        } else {
+            let dirs: std::collections::BTreeSet<&Path> = changes.paths.iter()
+                .filter_map(|path| path.parent())
+                .collect();
+            for dir in dirs {
+                if let Err(error) = self.workspace.refresh_dir(dir) {
+                    errors.push(format!("{}: {error}", dir.display()));
                 }
             }
-            WheelMovement::Pixels { y, .. } => {
-                if y.is_sign_negative() {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::ScrollDown);
-                } else {
-                    self.buffers.current_buffer_mut().perform_action(EditAction::ScrollUp);
+        }
+
+        self.scan_git_status();
+
+        // Paths that no longer exist can't be canonicalized, so they are matched as they are.
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let changed: HashSet<PathBuf> = changes.paths.iter()
+            .flat_map(|path| [path.clone(), canonical(path)])
+            .collect();
+        for buffer in self.buffers.iter_mut() {
+            let Some(path) = buffer.path().map(canonical) else { continue; };
+            if !changes.rescan && !changed.contains(&path) {
+                continue;
+            }
+            match buffer.sync_with_disk() {
+                Ok(DiskChange::Conflict(disk)) => {
+                    let text = buffer.text();
+                    let diff = similar::TextDiff::from_lines(&text, &disk)
+                        .unified_diff()
+                        .context_radius(1)
+                        .to_string()
+                        .lines()
+                        .map(str::to_string)
+                        .collect();
+                    let conflict = FileConflict { buffer_id: buffer.id, path, disk, diff };
+                    // Only the latest change to a file matters.
+                    self.conflicts.retain(|other| other.buffer_id != buffer.id);
+                    self.conflicts.push(conflict);
+                }
+                Ok(DiskChange::Removed) => {
+                    let name = self.workspace.relative_name(&path);
+                    errors.push(format!("{name} was removed, saving writes it back"));
                 }
+                Ok(DiskChange::Reloaded | DiskChange::None) => {}
+                Err(error) => errors.push(error.to_string()),
             }
         }
+        if !errors.is_empty() {
+            self.show_error(errors.join("; "));
+        }
     }
 
-    fn window_desc(&self) -> WindowDescriptor {
-        WindowDescriptor {
-            title: "Rust Editor",
-            ..Default::default()
+    /// Starts reading the git status of the workspace again, once any scan that is running has
+    /// finished.
+    fn scan_git_status(&mut self) {
+        if !self.workspace.info.is_git() {
+            return;
+        }
+        if self.status_scan.is_some() {
+            self.status_stale = true;
+        } else {
+            self.status_scan = Some(StatusScan::spawn(self.workspace.info.path.clone()));
         }
     }
-}
 
+    /// Takes the result of the status scan if it has finished. Returns whether a scan is still
+    /// running.
+    fn poll_git_status(&mut self) -> bool {
+        let Some(result) = self.status_scan.as_ref().and_then(StatusScan::poll) else {
+            return self.status_scan.is_some();
+        };
+        self.status_scan = None;
+        match result {
+            Ok(status) => self.git_status = status,
+            Err(error) => self.show_error(error),
+        }
+        // Something may have been committed, so the buffers' committed text may have changed too.
+        for buffer in self.buffers.iter_mut() {
+            buffer.head_stale = true;
+        }
+        self.blame = None;
+        if std::mem::take(&mut self.status_stale) {
+            self.scan_git_status();
+        }
 
+        self.status_scan.is_some()
+    }
 
-pub struct BufferSet {
-    buffers: Vec<Buffer>,
-    current: usize,
-}
+    /// Handles a key press while a conflict is shown. Returns whether the key was used.
+    fn conflict_key_down(&mut self, code: KeyCode) -> bool {
+        if self.conflicts.is_empty() || self.keys_down.contains(&KeyCode::C_LCTRL) {
+            return false;
+        }
+        let reload = match code {
+            KeyCode::C_ESCAPE => false,
+            other => match util::keycode_to_char(other) {
+                Some('r') => true,
+                Some('k') => false,
+                _ => return true,
+            },
+        };
 
-impl BufferSet {
-    pub fn new(initial_buffer_path: PathBuf, initial_buffer_content: &str) -> Self {
+        let conflict = self.conflicts.remove(0);
+        if let Some(buffer) = self.buffers.buffer_with_id_mut(conflict.buffer_id) {
+            if reload {
+                buffer.reload(&conflict.disk);
+            } else {
+                buffer.keep_over_disk(&conflict.disk);
+            }
+        }
+
+        true
+    }
+
+    /// Scrolls the current buffer by the given number of lines. Positive amounts scroll up.
+    fn scroll_lines(&mut self, lines: isize) {
+        let action = if lines < 0 { EditAction::ScrollDown } else { EditAction::ScrollUp };
+        for _ in 0..lines.unsigned_abs() {
+            self.perform_action(action);
+        }
+    }
+
+    /// Opens the search prompt, starting from the selected text if there is any on one line.
+    /// Text selected across lines, or by several cursors, is searched in instead.
+    fn open_search(&mut self, replace: bool, workspace: bool) {
+        self.picker = None;
+        let buffer = self.buffers.current_buffer_mut();
+        let selections = buffer.all_selection_bounds();
+        let in_selection = selections.len() > 1
+            || selections.first().is_some_and(|(start, end)| start.line != end.line);
+        let (origin, query) = match buffer.selection_bounds() {
+            Some((start, end)) if !in_selection => (start, buffer.text_range(start, end)),
+            _ => (buffer.cursor, String::new()),
+        };
+        let options = buffer.search().map(Search::options).unwrap_or_default();
+        self.search_prompt = Some(SearchPrompt {
+            editing_replace: replace && !query.is_empty(),
+            query,
+            options,
+            origin,
+            error: None,
+            replace: replace.then(Replace::default),
+            in_selection: replace && in_selection,
+            preview: vec![],
+            preview_version: None,
+            replaced: None,
+            workspace,
+        });
+        self.update_search();
+    }
+
+    /// Closes the search prompt, if it is open, and clears the search.
+    fn close_search(&mut self) {
+        if self.search_prompt.take().is_some() {
+            self.buffers.current_buffer_mut().set_search(None);
+        }
+    }
+
+    /// Compiles the prompt's query and selects its first match after where the search started.
+    fn update_search(&mut self) {
+        let Some(prompt) = &mut self.search_prompt else { return; };
+        let buffer = self.buffers.current_buffer_mut();
+        prompt.preview_version = None;
+        if prompt.workspace {
+            // Files are only searched once the query is submitted, but mistakes are shown early.
+            prompt.error = Search::new(&prompt.query, prompt.options).err().map(search_error);
+            return;
+        }
+        if prompt.query.is_empty() {
+            prompt.error = None;
+            buffer.set_search(None);
+            return;
+        }
+
+        match Search::new(&prompt.query, prompt.options) {
+            Ok(search) => {
+                prompt.error = None;
+                buffer.set_search(Some(search));
+                // The selections being searched in must stay where they are.
+                if !prompt.in_selection && !buffer.select_next_match(prompt.origin) {
+                    buffer.clear_selection();
+                }
+            }
+            Err(error) => {
+                // Keep showing the matches of the last valid query while a regex is being typed.
+                prompt.error = Some(search_error(error));
+            }
+        }
+    }
+
+    /// Opens the file picker, indexing the workspace's files again so that the picker is never
+    /// out of date.
+    fn open_picker(&mut self) {
+        self.close_search();
+        let root = self.workspace.info.path.clone();
+        self.picker = Some(FilePicker {
+            query: String::new(),
+            recent: relative_paths(&root, self.buffers.recent_files()),
+            index: FileIndex::spawn(root),
+            matches: vec![],
+            selected: 0,
+        });
+    }
+
+    /// Handles a key press while the file picker is open. Returns whether the key was used.
+    fn picker_key_down(&mut self, code: KeyCode) -> bool {
+        let Some(picker) = &mut self.picker else { return false; };
+        match code {
+            KeyCode::C_ESCAPE => {
+                self.picker = None;
+            }
+            KeyCode::C_ENTER => {
+                let Some(&index) = picker.matches.get(picker.selected) else { return true; };
+                let path = picker.index.root().join(&picker.index.files()[index]);
+                self.picker = None;
+                if let Err(error) = self.buffers.open(&path) {
+                    self.show_error(error);
+                }
+            }
+            KeyCode::C_ARROWUP => {
+                picker.selected = picker.selected.saturating_sub(1);
+            }
+            KeyCode::C_ARROWDOWN => {
+                picker.selected = (picker.selected + 1).min(picker.matches.len().saturating_sub(1));
+            }
+            KeyCode::C_BACKSPACE => {
+                picker.query.pop();
+                picker.selected = 0;
+                picker.update_matches();
+            }
+            KeyCode::C_SPACE => {
+                picker.query.push(' ');
+                picker.selected = 0;
+                picker.update_matches();
+            }
+            other => {
+                let Some(ch) = util::keycode_to_char(other) else { return false; };
+                if self.keys_down.contains(&KeyCode::C_LCTRL) {
+                    return false;
+                }
+                let shift = self.keys_down.contains(&KeyCode::C_LSHIFT);
+                picker.query.push(if shift { util::shifted_char(ch) } else { ch });
+                picker.selected = 0;
+                picker.update_matches();
+            }
+        }
+
+        true
+    }
+
+    /// Searches every file in the workspace for the prompt's query, listing the hits in a new
+    /// results buffer. Any workspace search that is still running is stopped.
+    fn start_grep(&mut self) {
+        let Some(prompt) = self.search_prompt.take() else { return; };
+        let search = match Search::new(&prompt.query, prompt.options) {
+            Ok(search) if !prompt.query.is_empty() => search,
+            Ok(_) => return,
+            Err(error) => {
+                self.search_prompt = Some(SearchPrompt { error: Some(search_error(error)), ..prompt });
+                return;
+            }
+        };
+
+        let mut buffer = Buffer::new(BufferKind::Other, &format!("Results for `{}`", prompt.query));
+        // The hits are found by the line of the cursor.
+        buffer.read_only = true;
+        let buffer_id = buffer.id;
+        self.buffers.add(buffer);
+        self.grep = Some(GrepResults {
+            grep: WorkspaceGrep::spawn(self.workspace.info.path.clone(), search),
+            buffer_id,
+            hits: vec![],
+            file_count: 0,
+        });
+    }
+
+    /// Lists any new hits of the workspace search in its results buffer. Returns whether the
+    /// search is still running.
+    fn poll_grep(&mut self) -> bool {
+        let Some(results) = &mut self.grep else { return false; };
+        if results.grep.is_done() {
+            return false;
+        }
+        let hits = results.grep.poll();
+        let Some(buffer) = self.buffers.buffer_with_id_mut(results.buffer_id) else {
+            // The results have nowhere to go.
+            self.grep = None;
+            return false;
+        };
+
+        let mut lines = String::new();
+        for hit in &hits {
+            if results.hits.last().is_none_or(|last| last.path != hit.path) {
+                results.file_count += 1;
+            }
+            let path = hit.path.strip_prefix(&self.workspace.info.path).unwrap_or(&hit.path);
+            lines.push_str(&format!(
+                "\n{}:{}:{}: {}",
+                path.display(),
+                hit.line + 1,
+                hit.column + 1,
+                hit.preview,
+            ));
+            results.hits.push(hit.clone());
+        }
+        if results.grep.is_done() {
+            let noun = if results.hits.len() == 1 { "match" } else { "matches" };
+            let files = if results.file_count == 1 { "file" } else { "files" };
+            lines.push_str(&format!(
+                "\n\n{} {noun} in {} {files}",
+                results.hits.len(),
+                results.file_count,
+            ));
+        }
+        buffer.append(&lines);
+
+        !results.grep.is_done()
+    }
+
+    /// Opens the workspace search hit on the cursor's line, if the current buffer lists them.
+    /// Returns whether there was a hit to open.
+    fn goto_grep_hit(&mut self) -> bool {
+        let Some(results) = &self.grep else { return false; };
+        let buffer = self.buffers.current_buffer();
+        if buffer.id != results.buffer_id {
+            return false;
+        }
+        // The first line is the header.
+        let Some(hit) = buffer.cursor.line.checked_sub(1).and_then(|index| results.hits.get(index))
+        else {
+            return false;
+        };
+        // Opened buffers aren't sized until they are drawn, but need to be to scroll the hit into
+        // view.
+        let (rows, cols) = (buffer.rows, buffer.cols);
+
+        if let Err(error) = self.buffers.open(&hit.path) {
+            self.show_error(error);
+            return true;
+        }
+        let buffer = self.buffers.current_buffer_mut();
+        if buffer.rows == 0 {
+            buffer.rows = rows;
+            buffer.cols = cols;
+        }
+        let start = Cursor { line: hit.line, index: hit.range.start };
+        let end = Cursor { line: hit.line, index: hit.range.end };
+        if !buffer.select_range(start, end) {
+            // The file has changed since it was searched, so only its line can be found.
+            let line_start = Cursor { line: start.line, index: 0 };
+            buffer.select_range(line_start, line_start);
+        }
+
+        true
+    }
+
+    /// Lists how the current buffer differs from its file in the last commit in a new buffer.
+    fn open_head_diff(&mut self) {
+        let buffer = self.buffers.current_buffer_mut();
+        buffer.update_hunks();
+        let Some(path) = buffer.path() else {
+            self.show_error("Only files can be compared with the last commit");
+            return;
+        };
+        let name = self.workspace.relative_name(path);
+        let Some(head_text) = &buffer.head_text else {
+            self.show_error(format!("{name} isn't in the last commit"));
+            return;
+        };
+        if buffer.hunks().is_empty() {
+            self.show_error(format!("{name} hasn't changed since the last commit"));
+            return;
+        }
+
+        let old_lines: Vec<&str> = head_text.split('\n').collect();
+        let new_lines: Vec<Cow<'_, str>> = buffer.text.lines().collect();
+        let new_lines: Vec<&str> = new_lines.iter().map(|line| line.as_ref()).collect();
+        let diff = unified_diff(&old_lines, &new_lines, buffer.hunks(), DIFF_CONTEXT_LINES);
+        let mut content = format!("Changes to `{name}` since the last commit");
+        for line in &diff {
+            content.push('\n');
+            content.push_str(&line.text);
+        }
+        let source_id = buffer.id;
+
+        let mut diff_buffer = Buffer::new(BufferKind::Other, &content);
+        // Its lines are mapped to the compared buffer's by index.
+        diff_buffer.read_only = true;
+        let buffer_id = diff_buffer.id;
+        self.buffers.add(diff_buffer);
+        self.head_diff = Some(HeadDiff {
+            buffer_id,
+            source_id,
+            source_lines: diff.into_iter().map(|line| line.new_line).collect(),
+        });
+    }
+
+    /// Goes to the line of the compared buffer that the cursor's line of the diff against the
+    /// last commit is at, if the current buffer shows the diff. Returns whether it did.
+    fn goto_diff_line(&mut self) -> bool {
+        let Some(diff) = &self.head_diff else { return false; };
+        let buffer = self.buffers.current_buffer();
+        if buffer.id != diff.buffer_id {
+            return false;
+        }
+        // The first line is the header.
+        let Some(&line) = buffer.cursor.line.checked_sub(1)
+            .and_then(|index| diff.source_lines.get(index))
+        else {
+            return false;
+        };
+        if !self.buffers.goto_id(diff.source_id) {
+            return false;
+        }
+
+        let buffer = self.buffers.current_buffer_mut();
+        // The buffer may have been edited since it was compared.
+        let start = Cursor { line: line.min(buffer.text.len_lines() - 1), index: 0 };
+        buffer.select_range(start, start);
+
+        true
+    }
+
+    /// Takes the content of the current buffer's file in the last commit if it has been read, and
+    /// starts reading it again if it has gone out of date. Returns whether it is still being read.
+    fn poll_head(&mut self) -> bool {
+        if !self.workspace.info.is_git() {
+            return false;
+        }
+        let buffer = self.buffers.current_buffer_mut();
+        if let Some(result) = buffer.head_scan.as_ref().and_then(HeadScan::poll) {
+            buffer.head_scan = None;
+            match result {
+                Ok(head_text) => buffer.set_head_text(head_text),
+                Err(error) => {
+                    buffer.set_head_text(None);
+                    self.show_error(error);
+                }
+            }
+        }
+
+        let buffer = self.buffers.current_buffer_mut();
+        if buffer.head_stale && buffer.head_scan.is_none() {
+            buffer.head_stale = false;
+            buffer.head_scan = buffer.path().map(|path| HeadScan::spawn(path.to_path_buf()));
+        }
+
+        buffer.head_scan.is_some()
+    }
+
+    /// Takes the blame of the current buffer if it has been read, and starts reading it again if
+    /// the buffer has changed since. Returns whether the blame is still being read.
+    fn poll_blame(&mut self) -> bool {
+        if let Some(blame) = &mut self.blame
+            && let Some(result) = blame.scan.as_ref().and_then(BlameScan::poll)
+        {
+            blame.scan = None;
+            match result {
+                Ok(file_blame) => blame.blame = Some(file_blame),
+                Err(error) => self.show_error(error),
+            }
+        }
+        if self.blame.as_ref().is_some_and(|blame| blame.scan.is_some()) {
+            return true;
+        }
+        if !self.show_blame || !self.workspace.info.is_git() {
+            return false;
+        }
+
+        let buffer = self.buffers.current_buffer();
+        let Some(path) = buffer.path() else { return false; };
+        let is_current = |blame: &BufferBlame| {
+            blame.buffer_id == buffer.id && blame.version == buffer.version
+        };
+        if self.blame.as_ref().is_some_and(is_current) {
+            return false;
+        }
+        self.blame = Some(BufferBlame {
+            buffer_id: buffer.id,
+            version: buffer.version,
+            scan: Some(BlameScan::spawn(path.to_path_buf(), buffer.text())),
+            blame: None,
+        });
+
+        true
+    }
+
+    /// Handles a key press while the search prompt is open. Returns whether the key was used.
+    fn search_key_down(&mut self, code: KeyCode) -> bool {
+        let Some(prompt) = &mut self.search_prompt else { return false; };
+        let shift = self.keys_down.contains(&KeyCode::C_LSHIFT);
+        let alt = self.keys_down.contains(&KeyCode::C_LALT);
+        let buffer = self.buffers.current_buffer_mut();
+        prompt.replaced = None;
+        prompt.preview_version = None;
+
+        let field = match (&mut prompt.replace, prompt.editing_replace) {
+            (Some(replace), true) => &mut replace.template,
+            _ => &mut prompt.query,
+        };
+        match code {
+            KeyCode::C_ESCAPE => {
+                self.close_search();
+                return true;
+            }
+            KeyCode::C_TAB => {
+                prompt.editing_replace = prompt.replace.is_some() && !prompt.editing_replace;
+                return true;
+            }
+            KeyCode::C_ENTER if prompt.workspace => {
+                self.start_grep();
+                return true;
+            }
+            KeyCode::C_ENTER => {
+                match &prompt.replace {
+                    Some(replace) if alt => {
+                        prompt.replaced = Some(buffer.replace_all(replace, prompt.in_selection));
+                    }
+                    Some(replace) if prompt.editing_replace => {
+                        buffer.replace_next(replace);
+                    }
+                    _ => {
+                        let action = if shift { EditAction::FindPrevious } else { EditAction::FindNext };
+                        self.perform_action(action);
+                    }
+                }
+                return true;
+            }
+            KeyCode::C_BACKSPACE => {
+                field.pop();
+            }
+            KeyCode::C_SPACE => {
+                field.push(' ');
+            }
+            other => {
+                let Some(ch) = util::keycode_to_char(other) else { return false; };
+                if self.keys_down.contains(&KeyCode::C_LCTRL) {
+                    return false;
+                }
+                if alt {
+                    match ch {
+                        'c' => prompt.options.case_insensitive = !prompt.options.case_insensitive,
+                        'w' => prompt.options.whole_word = !prompt.options.whole_word,
+                        'r' => prompt.options.regex = !prompt.options.regex,
+                        's' if prompt.replace.is_some() => {
+                            prompt.in_selection = !prompt.in_selection;
+                        }
+                        'p' => {
+                            if let Some(replace) = &mut prompt.replace {
+                                replace.preserve_case = !replace.preserve_case;
+                            }
+                            return true;
+                        }
+                        _ => return true,
+                    }
+                    // Options change the search itself.
+                    self.update_search();
+                    return true;
+                }
+                field.push(if shift { util::shifted_char(ch) } else { ch });
+            }
+        }
+        // Editing the replacement only changes the preview.
+        if !prompt.editing_replace {
+            self.update_search();
+        }
+
+        true
+    }
+
+    /// Moves the cursors, extending their selections while shift is held and clearing them
+    /// otherwise.
+    fn perform_motion(&mut self, action: EditAction) {
+        if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+            self.buffers.current_buffer_mut().start_or_continue_selection();
+        } else {
+            self.buffers.current_buffer_mut().clear_selection();
+        }
+        self.perform_action(action);
+    }
+}
+
+impl AppHandler for App {
+    fn startup(&mut self, cx: AppContext) {
+        // cx.renderer.load_font(include_bytes!("../data/JetBrainsMonoNerdFont_Regular.ttf"));
+        cx.renderer.set_monospace_family("JetBrainsMono Nerd Font");
+
+        self.cell_size = cx.renderer.measure_text(&Text {
+            content: "█".into(),
+            size: 17.0,
+            font_family: FontFamily::Monospace,
+            ..Default::default()
+        });
+    }
+
+    fn render<'pass>(&'pass mut self, cx: AppContext, layers: &mut LayerStack<'pass>) {
+        // Redraws only for the watcher wait until it is due, rather than checking it as fast as
+        // frames can be drawn.
+        if let Some(due) = self.watch_due.take() {
+            std::thread::sleep(due.saturating_duration_since(std::time::Instant::now()));
+        }
+        self.apply_file_changes();
+        let mut redraw = false;
+        for result in self.highlights.results() {
+            if let Some(buffer) = self.buffers.buffer_with_id_mut(result.buffer_id) {
+                buffer.apply_highlights(result);
+            }
+        }
+        if self.poll_grep() {
+            // Keep checking for hits until the whole workspace has been searched.
+            redraw = true;
+        }
+        if self.poll_git_status() {
+            redraw = true;
+        }
+        if self.poll_head() {
+            redraw = true;
+        }
+        if self.poll_blame() {
+            redraw = true;
+        }
+        if let Some(picker) = &mut self.picker {
+            if picker.index.poll() > 0 {
+                picker.update_matches();
+            }
+            if !picker.index.is_done() {
+                redraw = true;
+            }
+        }
+
+        // Keep scrolling while a selection is dragged past the top or bottom of the buffer.
+        if self.dragging {
+            let top = self.buffer_area.y;
+            let bottom = self.buffer_area.y + self.buffer_area.h;
+            if self.mouse_pos.y < top || self.mouse_pos.y >= bottom {
+                self.scroll_lines(if self.mouse_pos.y < top { 1 } else { -1 });
+                self.perform_action(EditAction::Drag(self.cell_at(self.mouse_pos)));
+                redraw = true;
+            }
+        }
+
+        let theme = &self.themes[self.theme];
+        let buffer = self.buffers.current_buffer_mut();
+
+        layers.start_layer(cx.renderer.viewport_rect());
+        layers.fill_quad(Quad {
+            bounds: cx.renderer.viewport_rect(),
+            bg_color: theme.background,
+            ..Default::default()
+        });
+        layers.end_layer();
+        layers.start_layer(cx.renderer.viewport_rect());
+
+        let (side_area, buffer_area) = cx.renderer.viewport_rect().hsplit_portion(0.2);
+
+        let (header_area, files_area) = side_area.vsplit_len(37.0);
+        let header_area = header_area.shrink(7.0, 7.0);
+        let files_area = files_area.shrink_h(11.0);
+        self.files_area = files_area;
+
+        let header_text = self.workspace.info.path.file_name()
+            .and_then(|os_str| os_str.to_str())
+            .unwrap();
+
+        layers.fill_text(Text {
+            content: header_text.into(),
+            color: theme.header_text,
+            size: 13.0,
+            bounds: header_area,
+            ..Default::default()
+        });
+
+        // Keep the selected entry in view while the file tree has focus.
+        let visible_entries = (files_area.h / ENTRY_HEIGHT).floor().max(1.0) as usize;
+        if self.files_focused && let Some(index) = self.workspace.selected_index() {
+            if index < self.files_scroll {
+                self.files_scroll = index;
+            } else if index >= self.files_scroll + visible_entries {
+                self.files_scroll = index + 1 - visible_entries;
+            }
+        }
+        self.files_scroll = self.files_scroll
+            .min(self.workspace.entries().count().saturating_sub(visible_entries));
+
+        let mut y_offset = 0.0;
+        for entry in self.workspace.entries().skip(self.files_scroll).take(visible_entries) {
+            if self.files_focused && self.workspace.selected.as_deref() == Some(entry.path) {
+                layers.fill_quad(Quad {
+                    bounds: Rect::new(
+                        vec2(files_area.x, files_area.y + y_offset),
+                        vec2(files_area.w, ENTRY_HEIGHT),
+                    ),
+                    bg_color: theme.selection,
+                    ..Default::default()
+                });
+            }
+
+            let padding = 7.0 * entry.level as f32;
+            let content = match (entry.is_dir, entry.expanded) {
+                (true, true) => format!("▾ {}", entry.name),
+                (true, false) => format!("▸ {}", entry.name),
+                (false, _) => entry.name.to_string(),
+            };
+            layers.fill_text(Text {
+                content: content.into(),
+                color: if entry.ignored { theme.ignored_entry_text } else { theme.entry_text },
+                size: 11.0,
+                bounds: Rect::new(
+                    vec2(files_area.x + padding, files_area.y + y_offset),
+                    vec2(files_area.w - padding - STATUS_MARKER_WIDTH, ENTRY_HEIGHT),
+                ),
+                ..Default::default()
+            });
+            if let Some(status) = self.git_status.get(entry.path) {
+                let color = match status {
+                    FileStatus::Untracked | FileStatus::Added => theme.vc_added,
+                    FileStatus::Modified => theme.vc_modified,
+                    FileStatus::Conflicted => theme.vc_deleted,
+                };
+                layers.fill_text(Text {
+                    content: status.marker().into(),
+                    color,
+                    size: 11.0,
+                    bounds: Rect::new(
+                        vec2(
+                            files_area.x + files_area.w - STATUS_MARKER_WIDTH,
+                            files_area.y + y_offset,
+                        ),
+                        vec2(STATUS_MARKER_WIDTH, ENTRY_HEIGHT),
+                    ),
+                    ..Default::default()
+                });
+            }
+            y_offset += ENTRY_HEIGHT;
+        }
+
+        let (gutter_area, buffer_area) = buffer_area.hsplit_len(73.0);
+        // Replacing previews the lines it would change, which go out of date with any edit.
+        if let Some(prompt) = &mut self.search_prompt
+            && let Some(replace) = &prompt.replace
+            && prompt.preview_version != Some(buffer.version)
+        {
+            prompt.preview = buffer.replace_preview(replace, prompt.in_selection, 5);
+            prompt.preview_version = Some(buffer.version);
+        }
+        // The search prompt or file picker takes the bottom rows.
+        let prompt_rows = self.search_prompt.as_ref().map_or(0, |prompt| match prompt.replace {
+            Some(_) => 2 + prompt.preview.len(),
+            None => 1,
+        }) + self.picker.as_ref().map_or(0, |picker| {
+            1 + picker.matches.len().min(MAX_PICKER_ROWS)
+        }) + self.conflicts.first().map_or(0, |conflict| {
+            1 + conflict.diff.len().min(MAX_CONFLICT_ROWS)
+        });
+        // The file prompt and any error go on the last rows.
+        let mut status_lines = vec![];
+        if let Some(prompt) = &self.file_prompt {
+            let line = match &prompt.op {
+                FileOp::NewFile => format!("New file: {}", prompt.input),
+                FileOp::NewDir => format!("New directory: {}", prompt.input),
+                FileOp::Rename(path) => {
+                    format!("Rename {} to: {}", self.workspace.relative_name(path), prompt.input)
+                }
+                FileOp::Delete(path) => {
+                    format!("Move {} to the trash? [y/n]", self.workspace.relative_name(path))
+                }
+                FileOp::SaveAs => format!("Save as: {}", prompt.input),
+            };
+            status_lines.push(line);
+        }
+        status_lines.extend(self.message.clone());
+        let prompt_rows = prompt_rows + status_lines.len();
+        let (buffer_area, prompt_area) = buffer_area.vsplit_len(
+            buffer_area.h - self.cell_size.y * prompt_rows as f32,
+        );
+        self.gutter_area = gutter_area;
+        self.buffer_area = buffer_area;
+
+        let buffer_cols = (buffer_area.w / self.cell_size.x).floor() as usize;
+        let buffer_rows = (buffer_area.h / self.cell_size.y).floor() as usize;
+        buffer.cols = buffer_cols;
+        buffer.rows = buffer_rows;
+
+        if buffer.update_hunks_after(HUNK_DELAY) {
+            // Keep checking until the buffer has gone long enough without edits.
+            redraw = true;
+        }
+
+        buffer.request_highlights(&self.highlights);
+        if buffer.is_highlighting() {
+            // Keep checking for results until the worker has caught up.
+            redraw = true;
+        }
+
+        // Matches are counted a chunk at a time, so that searching a large file doesn't freeze
+        // the editor.
+        if buffer.count_search_matches(10_000) {
+            redraw = true;
+        }
+        if redraw {
+            cx.window.request_redraw();
+        } else if self.watcher.is_some() {
+            // The watcher can't wake the window, so it is checked on a timer instead.
+            self.watch_due = Some(std::time::Instant::now() + WATCH_INTERVAL);
+            cx.window.request_redraw();
+        }
+
+        let cursors: Vec<Cursor> = buffer.cursors().map(|(cursor, _)| cursor).collect();
+        // The row and column of each cursor, if it is visible.
+        let mut cursor_positions: Vec<Option<(usize, usize)>> = vec![None; cursors.len()];
+        let mut last_line_index = 1;
+        let selections = buffer.all_selection_bounds();
+        let mut matches = vec![];
+        // Where the cursor's line ends, if it is visible.
+        let mut cursor_line_end = None;
+        let mut y_offset = 0.0;
+        for (index, row) in buffer.visible_rows().enumerate() {
+            if row.line_index != last_line_index {
+                // Mark the lines that have changed since the last commit at the edge of the
+                // gutter, and where lines were removed with a line above the first line after them.
+                let marker_x = gutter_area.x + gutter_area.w - CHANGE_MARKER_WIDTH * 2.0;
+                match line_change(buffer.hunks(), row.line_index) {
+                    Some(change @ (LineChange::Added | LineChange::Modified)) => {
+                        layers.fill_quad(Quad {
+                            bounds: Rect::new(
+                                vec2(marker_x, gutter_area.y + y_offset),
+                                vec2(CHANGE_MARKER_WIDTH, self.cell_size.y),
+                            ),
+                            bg_color: if change == LineChange::Added {
+                                theme.vc_added
+                            } else {
+                                theme.vc_modified
+                            },
+                            ..Default::default()
+                        });
+                    }
+                    Some(LineChange::DeletedAbove) => {
+                        layers.fill_quad(Quad {
+                            bounds: Rect::new(
+                                vec2(marker_x, gutter_area.y + y_offset - 1.0),
+                                vec2(CHANGE_MARKER_WIDTH * 2.0, 2.0),
+                            ),
+                            bg_color: theme.vc_deleted,
+                            ..Default::default()
+                        });
+                    }
+                    None => {}
+                }
+
+                layers.fill_text(Text {
+                    content: format!("{}", row.line_index + 1).into(),
+                    color: if row.line_index == buffer.cursor.line {
+                        theme.gutter_current_text
+                    } else {
+                        theme.gutter_text
+                    },
+                    size: 17.0,
+                    bounds: Rect::new(
+                        vec2(gutter_area.x, gutter_area.y + y_offset),
+                        gutter_area.size(),
+                    ),
+                    font_family: FontFamily::Monospace,
+                    ..Default::default()
+                });
+            }
+
+            // Highlight search matches, beneath any selections.
+            if row.index == 0 {
+                matches = buffer.line_matches(row.line_index);
+            }
+            for cols in matches.iter().filter_map(|(start, end)| row.columns_between(*start, *end)) {
+                layers.fill_quad(Quad {
+                    bounds: Rect::new(
+                        vec2(
+                            buffer_area.x + (self.cell_size.x * cols.start as f32),
+                            buffer_area.y + y_offset,
+                        ),
+                        vec2(self.cell_size.x * cols.len() as f32, self.cell_size.y),
+                    ),
+                    bg_color: theme.search_match,
+                    ..Default::default()
+                });
+            }
+
+            // Highlight selections.
+            for cols in selections.iter().filter_map(|(start, end)| row.columns_between(*start, *end)) {
+                layers.fill_quad(Quad {
+                    bounds: Rect::new(
+                        vec2(
+                            buffer_area.x + (self.cell_size.x * cols.start as f32),
+                            buffer_area.y + y_offset,
+                        ),
+                        vec2(self.cell_size.x * cols.len() as f32, self.cell_size.y),
+                    ),
+                    bg_color: theme.selection,
+                    ..Default::default()
+                });
+            }
+
+            for (cursor, position) in cursors.iter().zip(&mut cursor_positions) {
+                if row.line_index == cursor.line
+                    && row.start <= cursor.index
+                    && cursor.index <= row.start + row.content.len()
+                {
+                    *position = Some((index, row.column(cursor.index)));
+                }
+            }
+
+            for (range, scope) in buffer.row_spans(&row) {
+                let col = row.content[..range.start].graphemes(true).count();
+                let content = match &row.content {
+                    Cow::Borrowed(content) => Cow::Borrowed(&content[range]),
+                    Cow::Owned(content) => Cow::Owned(content[range].to_string()),
+                };
+                layers.fill_text(Text {
+                    content,
+                    color: theme.scope_color(scope),
+                    size: 17.0,
+                    bounds: Rect::new(
+                        vec2(
+                            buffer_area.x + (self.cell_size.x * col as f32),
+                            buffer_area.y + y_offset,
+                        ),
+                        buffer_area.size(),
+                    ),
+                    font_family: FontFamily::Monospace,
+                    ..Default::default()
+                });
+            }
+            if row.line_index == buffer.cursor.line {
+                cursor_line_end = Some((y_offset, row.column(row.start + row.content.len())));
+            }
+            last_line_index = row.line_index;
+
+            y_offset += self.cell_size.y;
+        }
+
+        // Show the commit that last changed the cursor's line after it, once the buffer as it is
+        // has been blamed.
+        let line_blame = self.blame.as_ref()
+            .filter(|blame| blame.buffer_id == buffer.id && blame.version == buffer.version)
+            .and_then(|blame| blame.blame.as_ref())
+            .map(|blame| match blame.line(buffer.cursor.line) {
+                Some(line) => line.to_string(),
+                None => "Not committed yet".to_string(),
+            });
+        if let (Some(content), Some((y_offset, col))) = (line_blame, cursor_line_end) {
+            layers.fill_text(Text {
+                content: content.into(),
+                color: theme.gutter_text,
+                size: 17.0,
+                bounds: Rect::new(
+                    vec2(
+                        buffer_area.x + self.cell_size.x * (col + BLAME_GAP_COLUMNS) as f32,
+                        buffer_area.y + y_offset,
+                    ),
+                    buffer_area.size(),
+                ),
+                font_family: FontFamily::Monospace,
+                ..Default::default()
+            });
+        }
+
+        // Draw cursors.
+        for (cursor_row, cursor_col) in cursor_positions.into_iter().flatten() {
+            layers.fill_quad(Quad {
+                bounds: Rect::new(
+                    vec2(
+                        buffer_area.x
+                            + (self.cell_size.x * cursor_col as f32)
+                            - 1.0, // Offset here so it is exactly centered.
+                        buffer_area.y + (self.cell_size.y * cursor_row as f32),
+                    ),
+                    vec2(2.0, self.cell_size.y),
+                ),
+                bg_color: theme.cursor,
+                ..Default::default()
+            });
+        }
+
+        if let Some(prompt) = &self.search_prompt {
+            let mut lines: Vec<(String, Color)> = prompt.preview.iter()
+                .map(|preview| {
+                    let line = format!(
+                        "{}: {}  ->  {}",
+                        preview.line_index + 1,
+                        preview.before.trim(),
+                        preview.after.trim(),
+                    );
+                    (line, theme.gutter_text)
+                })
+                .collect();
+
+            let marker = |editing: bool| match (&prompt.replace, editing) {
+                (Some(_), true) => "> ",
+                (Some(_), false) => "  ",
+                (None, _) => "",
+            };
+            let label = if prompt.workspace { "Find in workspace" } else { "Find" };
+            let mut find = format!("{}{label}: {}", marker(!prompt.editing_replace), prompt.query);
+            if let Some(error) = &prompt.error {
+                find.push_str(&format!("  ({error})"));
+            } else if let Some((count, done)) = buffer.search_count().filter(|_| !prompt.workspace) {
+                let plus = if done { "" } else { "+" };
+                let noun = if count == 1 && done { "match" } else { "matches" };
+                find.push_str(&format!("  ({count}{plus} {noun})"));
+            }
+            for (enabled, name) in [
+                (prompt.options.case_insensitive, "case insensitive"),
+                (prompt.options.whole_word, "whole word"),
+                (prompt.options.regex, "regex"),
+            ] {
+                if enabled {
+                    find.push_str(&format!("  [{name}]"));
+                }
+            }
+            lines.push((find, theme.header_text));
+
+            if let Some(replace) = &prompt.replace {
+                let mut line = format!("{}Replace: {}", marker(prompt.editing_replace), replace.template);
+                if let Some(replaced) = prompt.replaced {
+                    line.push_str(&format!("  ({replaced} replaced)"));
+                }
+                for (enabled, name) in [
+                    (replace.preserve_case, "preserve case"),
+                    (prompt.in_selection, "in selection"),
+                ] {
+                    if enabled {
+                        line.push_str(&format!("  [{name}]"));
+                    }
+                }
+                lines.push((line, theme.header_text));
+            }
+
+            for (index, (content, color)) in lines.into_iter().enumerate() {
+                layers.fill_text(Text {
+                    content: content.into(),
+                    color,
+                    size: 17.0,
+                    bounds: Rect::new(
+                        vec2(prompt_area.x, prompt_area.y + self.cell_size.y * index as f32),
+                        vec2(prompt_area.w, self.cell_size.y),
+                    ),
+                    font_family: FontFamily::Monospace,
+                    ..Default::default()
+                });
+            }
+        }
+
+        if let Some(picker) = &self.picker {
+            // Keep the selected file in view.
+            let first = picker.selected.saturating_sub(MAX_PICKER_ROWS - 1);
+            let rows = picker.matches.iter().skip(first).take(MAX_PICKER_ROWS);
+            let mut y_offset = 0.0;
+            for (n, &index) in rows.enumerate() {
+                let bounds = Rect::new(
+                    vec2(prompt_area.x, prompt_area.y + y_offset),
+                    vec2(prompt_area.w, self.cell_size.y),
+                );
+                if first + n == picker.selected {
+                    layers.fill_quad(Quad {
+                        bounds,
+                        bg_color: theme.selection,
+                        ..Default::default()
+                    });
+                }
+                layers.fill_text(Text {
+                    content: picker.index.files()[index].clone().into(),
+                    color: theme.text,
+                    size: 17.0,
+                    bounds,
+                    font_family: FontFamily::Monospace,
+                    ..Default::default()
+                });
+                y_offset += self.cell_size.y;
+            }
+
+            let plus = if picker.index.is_done() { "" } else { "+" };
+            let content = format!(
+                "Open: {}  ({}/{}{plus} files)",
+                picker.query,
+                picker.matches.len(),
+                picker.index.files().len(),
+            );
+            layers.fill_text(Text {
+                content: content.into(),
+                color: theme.header_text,
+                size: 17.0,
+                bounds: Rect::new(
+                    vec2(prompt_area.x, prompt_area.y + y_offset),
+                    vec2(prompt_area.w, self.cell_size.y),
+                ),
+                font_family: FontFamily::Monospace,
+                ..Default::default()
+            });
+        }
+
+        if let Some(conflict) = self.conflicts.first() {
+            let mut lines: Vec<(String, Color)> = conflict.diff.iter()
+                .take(MAX_CONFLICT_ROWS)
+                .map(|line| {
+                    let color = match line.chars().next() {
+                        Some('+') | Some('-') => theme.text,
+                        _ => theme.gutter_text,
+                    };
+                    (line.clone(), color)
+                })
+                .collect();
+            let more = match self.conflicts.len() {
+                1 => String::new(),
+                count => format!("  ({} more)", count - 1),
+            };
+            lines.push((
+                format!(
+                    "{} changed on disk with unsaved edits: [r]eload or [k]eep the buffer{more}",
+                    conflict.path.display(),
+                ),
+                theme.header_text,
+            ));
+
+            // Below the search prompt and file picker, if they are open.
+            let first_row = prompt_rows - status_lines.len() - lines.len();
+            for (index, (content, color)) in lines.into_iter().enumerate() {
+                layers.fill_text(Text {
+                    content: content.into(),
+                    color,
+                    size: 17.0,
+                    bounds: Rect::new(
+                        vec2(
+                            prompt_area.x,
+                            prompt_area.y + self.cell_size.y * (first_row + index) as f32,
+                        ),
+                        vec2(prompt_area.w, self.cell_size.y),
+                    ),
+                    font_family: FontFamily::Monospace,
+                    ..Default::default()
+                });
+            }
+        }
+
+        let first_row = prompt_rows - status_lines.len();
+        for (index, content) in status_lines.into_iter().enumerate() {
+            layers.fill_text(Text {
+                content: content.into(),
+                color: theme.header_text,
+                size: 17.0,
+                bounds: Rect::new(
+                    vec2(
+                        prompt_area.x,
+                        prompt_area.y + self.cell_size.y * (first_row + index) as f32,
+                    ),
+                    vec2(prompt_area.w, self.cell_size.y),
+                ),
+                font_family: FontFamily::Monospace,
+                ..Default::default()
+            });
+        }
+
+        layers.end_layer();
+    }
+
+    fn on_mouse_move(&mut self, cx: AppContext, x: f32, y: f32) {
+        self.mouse_pos = vec2(x, y);
+        if self.dragging {
+            self.perform_action(EditAction::Drag(self.cell_at(self.mouse_pos)));
+            self.watch_due = None;
+            cx.window.request_redraw();
+        }
+    }
+
+    fn on_primary_mouse_down(&mut self, cx: AppContext) {
+        self.watch_due = None;
+        cx.window.request_redraw();
+        let pos = self.mouse_pos;
+        if self.files_area.contains(pos) {
+            self.click_entry(pos);
+            return;
+        }
+        // Clicks in the gutter go to the start of the line.
+        if !self.gutter_area.contains(pos) && !self.buffer_area.contains(pos) {
+            return;
+        }
+        self.files_focused = false;
+
+        let cell = self.cell_at(pos);
+        self.dragging = true;
+        if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+            self.buffers.current_buffer_mut().start_or_continue_selection();
+            self.perform_action(EditAction::Drag(cell));
+            return;
+        }
+
+        let count = match self.last_click {
+            Some((time, last_cell, count))
+                if last_cell == cell && time.elapsed() < std::time::Duration::from_millis(400) =>
+            {
+                count % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((std::time::Instant::now(), cell, count));
+        match count {
+            1 => {
+                self.perform_action(EditAction::Click(cell));
+                self.buffers.current_buffer_mut().start_or_continue_selection();
+            }
+            2 => self.perform_action(EditAction::DoubleClick(cell)),
+            _ => self.perform_action(EditAction::TripleClick(cell)),
+        }
+    }
+
+    fn on_primary_mouse_up(&mut self, cx: AppContext) {
+        if !self.dragging {
+            return;
+        }
+        self.dragging = false;
+        self.watch_due = None;
+        cx.window.request_redraw();
+
+        // A click without a drag leaves nothing selected.
+        let buffer = self.buffers.current_buffer_mut();
+        if buffer.selection_bounds().is_some_and(|(start, end)| start == end) {
+            buffer.clear_selection();
+        }
+    }
+
+    fn on_key_down(&mut self, cx: AppContext, code: KeyCode, _repeat: bool) {
+        let _is_repeat = self.keys_down.insert(code);
+
+        self.watch_due = None;
+        cx.window.request_redraw();
+        self.message = None;
+        if self.conflict_key_down(code)
+            || self.picker_key_down(code)
+            || self.search_key_down(code)
+            || self.file_prompt_key_down(code)
+            || self.files_key_down(code)
+        {
+            return;
+        }
+        match code {
+            KeyCode::C_ARROWLEFT => {
+                if self.keys_down.contains(&KeyCode::C_LCTRL) {
+                    self.perform_motion(EditAction::MovePrevWord);
+                } else {
+                    self.perform_motion(EditAction::MoveLeft);
+                }
+            }
+            KeyCode::C_ARROWRIGHT => {
+                if self.keys_down.contains(&KeyCode::C_LCTRL) {
+                    self.perform_motion(EditAction::MoveNextWord);
+                } else {
+                    self.perform_motion(EditAction::MoveRight);
+                }
+            }
+            KeyCode::C_ARROWUP if self.keys_down.contains(&KeyCode::C_LCTRL)
+                && self.keys_down.contains(&KeyCode::C_LALT) =>
+            {
+                self.perform_action(EditAction::AddCursorAbove);
+            }
+            KeyCode::C_ARROWDOWN if self.keys_down.contains(&KeyCode::C_LCTRL)
+                && self.keys_down.contains(&KeyCode::C_LALT) =>
+            {
+                self.perform_action(EditAction::AddCursorBelow);
+            }
+            KeyCode::C_ARROWUP => {
+                self.perform_motion(EditAction::MoveUp);
+            }
+            KeyCode::C_ARROWDOWN => {
+                self.perform_motion(EditAction::MoveDown);
+            }
+
+            KeyCode::C_ESCAPE => {
+                self.perform_action(EditAction::CollapseCursors);
+                self.perform_action(EditAction::ClearSelection);
+            }
+
+            KeyCode::C_BACKSPACE => {
+                self.perform_action(EditAction::Backspace);
+            }
+            KeyCode::C_DELETE => {
+                self.perform_action(EditAction::Delete);
+            }
+
+            KeyCode::C_SPACE => {
+                self.perform_action(EditAction::Insert(' '));
+            }
+            // TODO: Indentation.
+            KeyCode::C_TAB => {
+                self.perform_action(EditAction::Insert('\t'));
+            }
+            KeyCode::C_ENTER => {
+                // Pressing enter on a workspace search hit opens it, and on a line of a diff
+                // against the last commit goes to it.
+                if !self.goto_grep_hit() && !self.goto_diff_line() {
+                    self.perform_action(EditAction::NewLine);
+                }
+            }
+
+            other => {
+                if let Some(ch) = util::keycode_to_char(other) {
+                    if self.keys_down.contains(&KeyCode::C_LCTRL) {
+                        match ch {
+                            '[' => {
+                                self.close_search();
+                                self.buffers.goto_previous(true);
+                            }
+                            ']' => {
+                                self.close_search();
+                                self.buffers.goto_next(true);
+                            }
+                            'z' => {
+                                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+                                    self.perform_action(EditAction::Redo);
+                                } else {
+                                    self.perform_action(EditAction::Undo);
+                                }
+                            }
+                            't' => {
+                                self.theme = (self.theme + 1) % self.themes.len();
+                            }
+                            'g' => {
+                                self.open_head_diff();
+                            }
+                            'j' => {
+                                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+                                    self.perform_action(EditAction::PreviousHunk);
+                                } else {
+                                    self.perform_action(EditAction::NextHunk);
+                                }
+                            }
+                            'r' => {
+                                self.perform_action(EditAction::RevertHunk);
+                            }
+                            'b' => {
+                                self.show_blame = !self.show_blame;
+                                if !self.show_blame {
+                                    self.blame = None;
+                                }
+                            }
+                            'f' => {
+                                let workspace = self.keys_down.contains(&KeyCode::C_LSHIFT);
+                                self.open_search(false, workspace);
+                            }
+                            'h' => {
+                                self.open_search(true, false);
+                            }
+                            'p' => {
+                                self.open_picker();
+                            }
+                            'e' => {
+                                self.files_focused = !self.files_focused;
+                                if self.files_focused && self.workspace.selected_index().is_none() {
+                                    self.workspace.select_index(0);
+                                }
+                            }
+                            'a' => {
+                                self.perform_action(EditAction::SelectAll);
+                            }
+                            'w' => {
+                                self.perform_action(EditAction::SelectWord);
+                            }
+                            'l' => {
+                                self.perform_action(EditAction::SelectLine);
+                            }
+                            'd' => {
+                                self.perform_action(EditAction::AddNextOccurrence);
+                            }
+                            'c' => {
+                                self.perform_action(EditAction::Copy);
+                            }
+                            'x' => {
+                                self.perform_action(EditAction::Cut);
+                            }
+                            'v' => {
+                                self.perform_action(EditAction::Paste);
+                            }
+                            'y' => {
+                                self.perform_action(EditAction::Redo);
+                            }
+                            's' => {
+                                let buffer = self.buffers.current_buffer_mut();
+                                if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+                                    let errors: Vec<String> = self.buffers.save_all()
+                                        .iter()
+                                        .map(ToString::to_string)
+                                        .collect();
+                                    if !errors.is_empty() {
+                                        self.show_error(errors.join("; "));
+                                    }
+                                } else if self.keys_down.contains(&KeyCode::C_LALT)
+                                    || buffer.path().is_none()
+                                {
+                                    // Buffers without a file are given one.
+                                    self.open_file_prompt(FileOp::SaveAs);
+                                } else if let Err(error) = buffer.save() {
+                                    self.show_error(error);
+                                }
+                            }
+                            _ => {}
+                        }
+                    } else if self.keys_down.contains(&KeyCode::C_LSHIFT) {
+                        let ch = util::shifted_char(ch);
+                        self.perform_action(EditAction::Insert(ch));
+                    } else {
+                        self.perform_action(EditAction::Insert(ch));
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_key_up(&mut self, _cx: AppContext, code: KeyCode) {
+        let _was_present = self.keys_down.remove(&code);
+    }
+
+    fn on_wheel_movement(&mut self, cx: AppContext, movement: WheelMovement) {
+        let lines = match movement {
+            WheelMovement::Lines { y, .. } => {
+                // Always scroll by at least a line.
+                let lines = y.abs().round().max(1.0) as isize;
+                if y.is_sign_negative() { -lines } else { lines }
+            }
+            WheelMovement::Pixels { y, .. } => {
+                // Touchpads send many small movements, which are added up until they make a line.
+                self.wheel_remainder += y;
+                let lines = (self.wheel_remainder / self.cell_size.y).trunc();
+                self.wheel_remainder -= lines * self.cell_size.y;
+                lines as isize
+            }
+        };
+        if lines != 0 && self.files_area.contains(self.mouse_pos) {
+            self.files_scroll = self.files_scroll.saturating_add_signed(-lines);
+            self.watch_due = None;
+            cx.window.request_redraw();
+        } else if lines != 0 {
+            self.scroll_lines(lines);
+            if self.dragging {
+                self.perform_action(EditAction::Drag(self.cell_at(self.mouse_pos)));
+            }
+            self.watch_due = None;
+            cx.window.request_redraw();
+        }
+    }
+
+    fn window_desc(&self) -> WindowDescriptor {
+        WindowDescriptor {
+            title: "Rust Editor",
+            ..Default::default()
+        }
+    }
+}
+
+
+
+/// The most files that [`BufferSet::recent_files`] remembers.
+const MAX_RECENT_FILES: usize = 64;
+
+pub struct BufferSet {
+    buffers: Vec<Buffer>,
+    current: usize,
+    /// The paths of the files that have been opened, from least to most recently opened.
+    recent: Vec<PathBuf>,
+}
+
+impl Default for BufferSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferSet {
+    pub fn new() -> Self {
         let scratch_buffer = Buffer::new(BufferKind::Other, "");
-        let current_buffer = Buffer::new(
-            BufferKind::File(initial_buffer_path),
-            initial_buffer_content,
-        );
 
         Self {
-            buffers: vec![scratch_buffer, current_buffer],
-            current: 1,
+            buffers: vec![scratch_buffer],
+            current: 0,
+            recent: vec![],
+        }
+    }
+
+    /// Opens the file at the given path and makes it the current buffer.
+    ///
+    /// If the file is already open, its buffer is made current instead of reading it again.
+    pub fn open(&mut self, path: &Path) -> std::result::Result<(), BufferError> {
+        match self.find_file(path) {
+            Some(index) => self.current = index,
+            None => self.add(Buffer::open(path)?),
+        }
+
+        self.recent.retain(|recent| recent != path);
+        if self.recent.len() == MAX_RECENT_FILES {
+            self.recent.remove(0);
+        }
+        self.recent.push(path.to_path_buf());
+
+        Ok(())
+    }
+
+    /// The paths of the files that have been opened, from least to most recently opened.
+    #[inline]
+    pub fn recent_files(&self) -> &[PathBuf] {
+        &self.recent
+    }
+
+    /// Saves every dirty buffer that is backed by a file, returning the errors for those that
+    /// could not be saved.
+    pub fn save_all(&mut self) -> Vec<BufferError> {
+        self.buffers.iter_mut()
+            .filter(|buffer| buffer.is_dirty() && buffer.path().is_some())
+            .filter_map(|buffer| buffer.save().err())
+            .collect()
+    }
+
+    /// Adds the given buffer and makes it the current buffer.
+    pub fn add(&mut self, buffer: Buffer) {
+        self.buffers.push(buffer);
+        self.current = self.count() - 1;
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Buffer> {
+        self.buffers.iter_mut()
+    }
+
+    /// The buffers whose files are at or under the given path, by ID, with the paths of their
+    /// files relative to it.
+    pub fn files_under(&self, path: &Path) -> Vec<(u64, PathBuf)> {
+        let Ok(path) = path.canonicalize() else { return vec![]; };
+        self.buffers.iter()
+            .filter_map(|buffer| {
+                let file = buffer.path()?.canonicalize().ok()?;
+                Some((buffer.id, file.strip_prefix(&path).ok()?.to_path_buf()))
+            })
+            .collect()
+    }
+
+    /// Makes the file at the given path the file of the buffer with the given ID, after its old
+    /// file has been moved there.
+    pub fn move_file(&mut self, id: u64, to: PathBuf) {
+        let Some(buffer) = self.buffers.iter_mut().find(|buffer| buffer.id == id) else { return; };
+        if let Some(from) = buffer.path().map(Path::to_path_buf) {
+            for recent in &mut self.recent {
+                if *recent == from {
+                    *recent = to.clone();
+                }
+            }
+        }
+        buffer.set_path(to);
+    }
+
+    pub fn buffer_with_id_mut(&mut self, id: u64) -> Option<&mut Buffer> {
+        self.buffers.iter_mut().find(|buffer| buffer.id == id)
+    }
+
+    /// Makes the buffer with the given ID the current buffer. Returns whether there is one.
+    pub fn goto_id(&mut self, id: u64) -> bool {
+        let Some(index) = self.buffers.iter().position(|buffer| buffer.id == id) else {
+            return false;
+        };
+        self.current = index;
+        true
+    }
+
+    /// Finds the index of the buffer for the file at the given path, if it is open.
+    pub fn find_file(&self, path: &Path) -> Option<usize> {
+        let path = path.canonicalize().ok()?;
+        self.buffers.iter().position(|buffer| {
+            buffer.path().and_then(|p| p.canonicalize().ok()).as_ref() == Some(&path)
+        })
+    }
+}
+
+impl BufferSet {
+    pub fn current_buffer(&self) -> &Buffer {
+        assert!(self.count() > 0); // Cannot close the only buffer.
+        &self.buffers[self.current]
+    }
+
+    pub fn current_buffer_mut(&mut self) -> &mut Buffer {
+        assert!(self.count() > 0); // Cannot close the only buffer.
+        &mut self.buffers[self.current]
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.buffers.len()
+    }
+
+    #[inline]
+    pub fn current_is_first(&self) -> bool {
+        self.current == 0
+    }
+
+    #[inline]
+    pub fn current_is_last(&self) -> bool {
+        self.count() == self.current + 1
+    }
+}
+
+impl BufferSet {
+    pub fn goto_next(&mut self, wrap_at_end: bool) -> bool {
+        if self.count() == 1 {
+            false
+        } else if self.current_is_last() {
+            if !wrap_at_end {
+                false
+            } else {
+                self.current = 0;
+                true
+            }
+        } else {
+            self.current += 1;
+            true
+        }
+    }
+
+    pub fn goto_previous(&mut self, wrap_at_start: bool) -> bool {
+        if self.count() == 1 {
+            false
+        } else if self.current_is_first() {
+            if !wrap_at_start {
+                false
+            } else {
+                self.current = self.count() - 1;
+                true
+            }
+        } else {
+            self.current -= 1;
+            true
+        }
+    }
+}
+
+
+
+pub struct Buffer {
+    /// An identifier that is unique to this buffer for the lifetime of the program.
+    id: u64,
+    /// Incremented with every edit to this buffer's text.
+    version: u64,
+    kind: BufferKind,
+    text: TextBuffer,
+    /// The highlighted scopes of each line, as last computed by the [`HighlightWorker`].
+    scopes: Vec<LineScopes>,
+    /// Whether the syntax of this buffer needs to be detected again.
+    needs_reparse: bool,
+    /// The edits that haven't yet been sent to the [`HighlightWorker`].
+    highlight_edits: Vec<LineEdit>,
+    /// Every edit made since the oldest version that results may still arrive for, with the
+    /// version that each edit produced.
+    highlight_log: Vec<(u64, LineEdit)>,
+    /// The visible lines when highlights were last requested.
+    highlight_visible: Range<usize>,
+    /// Whether the worker is still highlighting the latest version of this buffer.
+    highlight_pending: bool,
+    cursor: Cursor,
+    selection: Selection,
+    /// The column that vertical cursor motions try to keep the cursor in.
+    preferred_col: Option<usize>,
+    /// The cursors other than the primary one, in the order they appear in the text. Every edit
+    /// is made at each of them.
+    other_cursors: Vec<CursorState>,
+    rows: usize,
+    cols: usize,
+    /// The index of the first visible line.
+    scroll_line: usize,
+    /// The current search, whose matches are highlighted.
+    search: Option<SearchState>,
+    line_ending: LineEnding,
+    trailing_newline: bool,
+    /// Whether this buffer has been edited since it was last opened or saved.
+    dirty: bool,
+    /// Whether edits to this buffer's text are rejected, because its lines stand for something
+    /// else, like the hits of a workspace search.
+    read_only: bool,
+    /// The hash of the file's content when it was last read or written, for telling whether it
+    /// has been changed by something else.
+    disk_hash: u64,
+    /// The lines of this buffer's file in the last commit, if it is tracked by git.
+    head_text: Option<String>,
+    /// Whether `head_text` needs to be read again, because the file or repository has changed.
+    head_stale: bool,
+    /// The reading of `head_text` that is running, if any.
+    head_scan: Option<HeadScan>,
+    /// How this buffer's lines differ from `head_text`, as of `hunks_version`.
+    hunks: Vec<LineHunk>,
+    hunks_version: Option<u64>,
+    /// When this buffer's text was last edited, if it has been.
+    edited_at: Option<std::time::Instant>,
+    history: History,
+}
+
+pub enum BufferKind {
+    File(PathBuf),
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// Detects the line ending style used by the given content, based on its first line break.
+    pub fn detect(content: &str) -> Self {
+        match content.find('\n') {
+            Some(index) if content[..index].ends_with('\r') => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// What [`Buffer::sync_with_disk`] found.
+#[derive(Debug, Eq, PartialEq)]
+pub enum DiskChange {
+    /// The file hasn't changed.
+    None,
+    /// The file changed, and the buffer was clean so it was reloaded.
+    Reloaded,
+    /// The file changed while the buffer had unsaved edits. Holds the file's new content.
+    Conflict(String),
+    /// The file was removed or moved away. The buffer is marked dirty, so that saving it writes
+    /// the file back.
+    Removed,
+}
+
+#[derive(Debug)]
+pub enum BufferError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    InvalidUtf8 {
+        path: PathBuf,
+        /// The byte offset of the first invalid sequence.
+        valid_up_to: usize,
+    },
+    /// The buffer is not backed by a file, so it has nowhere to be saved to.
+    NoPath,
+}
+
+impl std::fmt::Display for BufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferError::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            BufferError::InvalidUtf8 { path, valid_up_to } => {
+                write!(f, "{}: invalid UTF-8 at byte {}", path.display(), valid_up_to)
+            }
+            BufferError::NoPath => {
+                write!(f, "buffer has no file path")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BufferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BufferError::Io { error, .. } => Some(error),
+            BufferError::InvalidUtf8 { .. } | BufferError::NoPath => None,
+        }
+    }
+}
+
+impl Buffer {
+    pub fn new(kind: BufferKind, content: &str) -> Self {
+        // The trailing newline is tracked separately, so that it isn't shown as an extra line.
+        let trailing_newline = content.ends_with('\n');
+        let text = if trailing_newline {
+            TextBuffer::new(strip_line_break(content))
+        } else {
+            TextBuffer::new(content)
+        };
+
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            version: 0,
+            kind,
+            scopes: vec![vec![]; text.len_lines()],
+            text,
+            needs_reparse: true,
+            highlight_edits: vec![],
+            highlight_log: vec![],
+            highlight_visible: 0..0,
+            highlight_pending: false,
+            cursor: Cursor { line: 0, index: 0 },
+            selection: Selection::None,
+            preferred_col: None,
+            other_cursors: vec![],
+            rows: 0, cols: 0, // Set by the render function in `App`.
+            scroll_line: 0,
+            search: None,
+            line_ending: LineEnding::detect(content),
+            trailing_newline,
+            dirty: false,
+            read_only: false,
+            disk_hash: content_hash(content.as_bytes()),
+            head_text: None,
+            head_stale: true,
+            head_scan: None,
+            hunks: vec![],
+            hunks_version: None,
+            edited_at: None,
+            history: History::default(),
+        }
+    }
+
+    /// Reads the file at the given path into a new [`BufferKind::File`] buffer.
+    pub fn open(path: &Path) -> std::result::Result<Self, BufferError> {
+        let bytes = std::fs::read(path).map_err(|error| BufferError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let content = String::from_utf8(bytes).map_err(|error| BufferError::InvalidUtf8 {
+            path: path.to_path_buf(),
+            valid_up_to: error.utf8_error().valid_up_to(),
+        })?;
+
+        Ok(Self::new(BufferKind::File(path.to_path_buf()), &content))
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match &self.kind {
+            BufferKind::File(path) => Some(path),
+            BufferKind::Other => None,
         }
     }
-}
 
-impl BufferSet {
-    pub fn current_buffer(&self) -> &Buffer {
-        assert!(self.count() > 0); // Cannot close the only buffer.
-        &self.buffers[self.current]
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
     }
 
-    pub fn current_buffer_mut(&mut self) -> &mut Buffer {
-        assert!(self.count() > 0); // Cannot close the only buffer.
-        &mut self.buffers[self.current]
+    /// Serializes the buffer's lines, using the line ending style and trailing newline of the
+    /// content it was created from.
+    pub fn text(&self) -> String {
+        let line_ending = self.line_ending.as_str();
+        let mut text = String::with_capacity(
+            self.text.len_bytes() + self.text.len_lines() * line_ending.len(),
+        );
+        for chunk in self.text.chunks() {
+            match self.line_ending {
+                LineEnding::Lf => text.push_str(chunk),
+                LineEnding::CrLf => text.push_str(&chunk.replace('\n', line_ending)),
+            }
+        }
+        if self.trailing_newline {
+            text.push_str(line_ending);
+        }
+
+        text
     }
 
-    #[inline]
-    pub fn count(&self) -> usize {
-        self.buffers.len()
+    /// Writes this buffer to its file.
+    pub fn save(&mut self) -> std::result::Result<(), BufferError> {
+        let BufferKind::File(path) = &self.kind else {
+            return Err(BufferError::NoPath);
+        };
+
+        self.write_to(&path.clone())
     }
 
-    #[inline]
-    pub fn current_is_first(&self) -> bool {
-        self.current == 0
+    /// Writes this buffer to the given path, and makes it the buffer's file. The buffer keeps
+    /// its file if the write fails.
+    pub fn save_as(&mut self, path: PathBuf) -> std::result::Result<(), BufferError> {
+        self.write_to(&path)?;
+        if self.path() != Some(path.as_path()) {
+            self.set_path(path);
+        }
+
+        Ok(())
     }
 
-    #[inline]
-    pub fn current_is_last(&self) -> bool {
-        self.count() == self.current + 1
+    fn write_to(&mut self, path: &Path) -> std::result::Result<(), BufferError> {
+        let text = self.text();
+        write_atomic(path, text.as_bytes()).map_err(|error| BufferError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        self.dirty = false;
+        self.disk_hash = content_hash(text.as_bytes());
+
+        Ok(())
     }
-}
 
-impl BufferSet {
-    pub fn goto_next(&mut self, wrap_at_end: bool) -> bool {
-        if self.count() == 1 {
-            false
-        } else if self.current_is_last() {
-            if !wrap_at_end {
-                false
-            } else {
-                self.current = 0;
-                true
+    /// Checks whether this buffer's file has been changed by something else since it was last
+    /// read or written. If it has, a clean buffer is reloaded, while a dirty buffer is left for
+    /// the caller to resolve with [`Buffer::reload`] or [`Buffer::keep_over_disk`].
+    ///
+    /// The text of buffers whose files have been removed is kept, so that it isn't lost.
+    pub fn sync_with_disk(&mut self) -> std::result::Result<DiskChange, BufferError> {
+        let BufferKind::File(path) = &self.kind else {
+            return Ok(DiskChange::None);
+        };
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                self.dirty = true;
+                return Ok(DiskChange::Removed);
             }
+            Err(error) => return Err(BufferError::Io { path: path.clone(), error }),
+        };
+        if content_hash(&bytes) == self.disk_hash {
+            return Ok(DiskChange::None);
+        }
+        let content = String::from_utf8(bytes).map_err(|error| BufferError::InvalidUtf8 {
+            path: path.clone(),
+            valid_up_to: error.utf8_error().valid_up_to(),
+        })?;
+
+        if self.dirty {
+            Ok(DiskChange::Conflict(content))
         } else {
-            self.current += 1;
-            true
+            self.reload(&content);
+            Ok(DiskChange::Reloaded)
         }
     }
 
-    pub fn goto_previous(&mut self, wrap_at_start: bool) -> bool {
-        if self.count() == 1 {
-            false
-        } else if self.current_is_first() {
-            if !wrap_at_start {
-                false
+    /// Replaces this buffer's text with the content of its file, as a single undoable
+    /// transaction. Only the lines that differ are changed, so that the cursors, scroll position
+    /// and highlights of the rest of the buffer are kept.
+    pub fn reload(&mut self, content: &str) {
+        let line_ending = LineEnding::detect(content);
+        let normalized = match line_ending {
+            LineEnding::Lf => Cow::Borrowed(content),
+            LineEnding::CrLf => Cow::Owned(content.replace("\r\n", "\n")),
+        };
+        let trailing_newline = normalized.ends_with('\n');
+        let new_lines: Vec<&str> = if trailing_newline {
+            strip_line_break(&normalized).split('\n').collect()
+        } else {
+            normalized.split('\n').collect()
+        };
+        let old_lines: Vec<String> = self.text.lines().map(Cow::into_owned).collect();
+        let old_lines: Vec<&str> = old_lines.iter().map(String::as_str).collect();
+        let hunks = diff_lines(&old_lines, &new_lines);
+
+        self.history.seal();
+        self.history.begin(self.cursor, self.selection);
+        let mut primary = CursorState {
+            cursor: self.cursor,
+            selection: self.selection,
+            preferred_col: self.preferred_col,
+        };
+        // Change the lines from the end, so that the indices of the lines before are unchanged.
+        for LineHunk { old, new } in hunks.into_iter().rev() {
+            let (start, end, new_end) = self.splice_lines(old, &new_lines[new]);
+            primary.map(|cursor| cursor.after_delete(start, end).after_insert(start, new_end));
+        }
+        self.cursor = primary.cursor;
+        self.selection = primary.selection;
+        self.merge_cursors();
+        self.history.commit(self.cursor);
+        self.history.seal();
+
+        self.line_ending = line_ending;
+        self.trailing_newline = trailing_newline;
+        self.dirty = false;
+        self.disk_hash = content_hash(content.as_bytes());
+    }
+
+    /// Replaces the lines in the given range with the given lines, either of which may be empty.
+    /// Returns the start and end of the replaced text, and the end of the text that replaced it.
+    fn splice_lines(
+        &mut self,
+        lines: Range<usize>,
+        replacement: &[&str],
+    ) -> (Cursor, Cursor, Cursor) {
+        let last_line = self.text.len_lines() - 1;
+        let line_start = |line| Cursor { line, index: 0 };
+        let line_end = |line| Cursor { line, index: self.text.line_len(line) };
+        let new_text = replacement.join("\n");
+
+        let (start, end, text) = if replacement.is_empty() {
+            // Removing lines also removes a line break, which is the one before them if they are
+            // the last lines.
+            if lines.end <= last_line {
+                (line_start(lines.start), line_start(lines.end), String::new())
+            } else if lines.start > 0 {
+                (line_end(lines.start - 1), line_end(last_line), String::new())
             } else {
-                self.current = self.count() - 1;
-                true
+                (line_start(0), line_end(last_line), String::new())
+            }
+        } else if lines.is_empty() {
+            // Likewise, adding lines adds a line break.
+            if lines.start <= last_line {
+                let start = line_start(lines.start);
+                (start, start, new_text + "\n")
+            } else {
+                let end = line_end(last_line);
+                (end, end, format!("\n{new_text}"))
             }
         } else {
-            self.current -= 1;
-            true
+            (line_start(lines.start), line_end(lines.end - 1), new_text)
+        };
+
+        self.delete_range(start, end);
+        let new_end = self.insert_at(start, &text);
+
+        (start, end, new_end)
+    }
+
+    /// Sets the content of this buffer's file in the last commit, which the buffer's changes are
+    /// found against.
+    pub fn set_head_text(&mut self, content: Option<String>) {
+        self.head_text = content.map(|content| {
+            let content = content.replace("\r\n", "\n");
+            match content.strip_suffix('\n') {
+                Some(stripped) => stripped.to_string(),
+                None => content,
+            }
+        });
+        self.hunks_version = None;
+    }
+
+    /// Finds how this buffer's lines differ from its file in the last commit again, if the buffer
+    /// has been edited since they were last found.
+    pub fn update_hunks(&mut self) {
+        if self.hunks_version == Some(self.version) {
+            return;
         }
+        self.hunks = match &self.head_text {
+            Some(head_text) => {
+                let old_lines: Vec<&str> = head_text.split('\n').collect();
+                let new_lines: Vec<Cow<'_, str>> = self.text.lines().collect();
+                let new_lines: Vec<&str> = new_lines.iter().map(|line| line.as_ref()).collect();
+                diff_lines(&old_lines, &new_lines)
+            }
+            None => vec![],
+        };
+        self.hunks_version = Some(self.version);
     }
-}
 
+    /// Finds how this buffer's lines differ from its file in the last commit again, once the
+    /// buffer has gone the given time without edits, so that the whole buffer isn't compared
+    /// with every key typed. Returns whether the changes are still waiting to be found.
+    pub fn update_hunks_after(&mut self, delay: std::time::Duration) -> bool {
+        if self.hunks_version == Some(self.version) {
+            return false;
+        }
+        if self.edited_at.is_some_and(|edited_at| edited_at.elapsed() < delay) {
+            return true;
+        }
+        self.update_hunks();
 
+        false
+    }
 
-pub struct Buffer {
-    kind: BufferKind,
-    lines: Vec<Line>,
-    scopes: Vec<(usize, Range<usize>, SourceScope)>,
-    needs_reparse: bool,
-    cursor: Cursor,
-    selection: Selection,
-    rows: usize,
-    cols: usize,
-    scroll_y_offset: u16,
-}
+    /// How this buffer's lines differ from its file in the last commit, as of the last call to
+    /// [`Buffer::update_hunks`].
+    #[inline]
+    pub fn hunks(&self) -> &[LineHunk] {
+        &self.hunks
+    }
 
-pub enum BufferKind {
-    File(PathBuf),
-    Other,
-}
+    /// The line that the given hunk is shown at, which for removed lines is the line after them,
+    /// or the last line if they were at the end.
+    fn hunk_line(&self, hunk: &LineHunk) -> usize {
+        hunk.new.start.min(self.text.len_lines() - 1)
+    }
 
-impl Buffer {
-    pub fn new(kind: BufferKind, content: &str) -> Self {
-        let mut lines: Vec<Line> = content.lines()
-            .map(|s| Line { content: s.to_string() })
-            .collect();
-        if lines.len() < 1 {
-            lines.push(Line { content: "".to_string() });
+    /// Finds the hunk that changed the line at the given index, or removed lines just above it.
+    fn hunk_at(&self, line_index: usize) -> Option<&LineHunk> {
+        self.hunks.iter().find(|hunk| {
+            hunk.new.contains(&line_index)
+                || hunk.new.is_empty() && self.hunk_line(hunk) == line_index
+        })
+    }
+
+    /// Moves the cursor to the start of the next hunk after its line, or the last before it,
+    /// wrapping around the buffer. Returns whether there was a hunk to move to.
+    fn goto_hunk(&mut self, forward: bool) -> bool {
+        self.update_hunks();
+        let lines: Vec<usize> = self.hunks.iter().map(|hunk| self.hunk_line(hunk)).collect();
+        let line = if forward {
+            lines.iter().find(|line| **line > self.cursor.line).or(lines.first())
+        } else {
+            lines.iter().rev().find(|line| **line < self.cursor.line).or(lines.last())
+        };
+        let Some(&line) = line else { return false; };
+        let start = Cursor { line, index: 0 };
+        self.select_range(start, start)
+    }
+
+    /// Replaces the lines of the hunk at the cursor's line with the lines they replaced in the
+    /// last commit. Returns whether there was a hunk to revert.
+    fn revert_hunk(&mut self) -> bool {
+        self.update_hunks();
+        let Some(hunk) = self.hunk_at(self.cursor.line).cloned() else { return false; };
+        let Some(head_text) = self.head_text.clone() else { return false; };
+        let head_lines: Vec<&str> = head_text.split('\n').collect();
+
+        self.splice_lines(hunk.new.clone(), &head_lines[hunk.old]);
+        self.cursor = Cursor { line: hunk.new.start.min(self.text.len_lines() - 1), index: 0 };
+        self.selection = Selection::None;
+        self.preferred_col = None;
+        self.scroll_to_cursor();
+
+        true
+    }
+
+    /// Keeps this buffer's text rather than the given content of its file, which won't be
+    /// treated as a change again. The buffer stays dirty, so saving it overwrites the file.
+    pub fn keep_over_disk(&mut self, content: &str) {
+        self.disk_hash = content_hash(content.as_bytes());
+    }
+
+    /// Makes the file at the given path this buffer's file, without writing to it.
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.kind = BufferKind::File(path);
+        self.needs_reparse = true;
+        self.head_stale = true;
+    }
+
+    /// Sends this buffer's text to the highlight worker, if it has changed since it was last sent.
+    ///
+    /// Buffers that aren't backed by a file, or whose file type is unknown, have no scopes.
+    pub fn request_highlights(&mut self, worker: &HighlightWorker) {
+        let visible = self.scroll_line..(self.scroll_line + self.rows).min(self.text.len_lines());
+        if !self.needs_reparse && self.highlight_edits.is_empty() && self.highlight_visible == visible {
+            return;
         }
 
-        Self {
-            kind,
-            lines,
-            scopes: vec![],
-            needs_reparse: true,
-            cursor: Cursor { line: 0, index: 0 },
-            selection: Selection::None,
-            rows: 0, cols: 0, // Set by the render function in `App`.
-            scroll_y_offset: 0,
+        let reset = std::mem::take(&mut self.needs_reparse);
+        if reset {
+            self.scopes = vec![vec![]; self.text.len_lines()];
         }
+        worker.request(HighlightRequest {
+            buffer_id: self.id,
+            version: self.version,
+            reset,
+            path: self.path().map(Path::to_path_buf),
+            edits: std::mem::take(&mut self.highlight_edits),
+            text: self.text.clone(),
+            visible: visible.clone(),
+        });
+        self.highlight_visible = visible;
+        self.highlight_pending = true;
     }
 
-    pub fn parse(&mut self, syntaxes: &syntect::parsing::SyntaxSet) {
-        let BufferKind::File(path) = &self.kind else { return; };
+    /// Applies highlighted scopes computed by the highlight worker.
+    ///
+    /// Results computed for an older version of this buffer are moved past the edits made since
+    /// then, dropping the lines those edits changed. The worker won't parse the other lines again,
+    /// so their scopes must not be lost.
+    pub fn apply_highlights(&mut self, result: HighlightResult) {
+        let edits: Vec<LineEdit> = self.highlight_log.iter()
+            .filter(|(version, _)| *version > result.version)
+            .map(|(_, edit)| *edit)
+            .collect();
+        for (line_index, scopes) in (result.start_line..).zip(result.scopes) {
+            let line_index = edits.iter()
+                .try_fold(line_index, |line_index, edit| edit.map_line(line_index));
+            if let Some(line_scopes) = line_index.and_then(|index| self.scopes.get_mut(index)) {
+                *line_scopes = scopes;
+            }
+        }
+
+        if result.version == self.version {
+            // Results arrive in order, so there are no older results left to move.
+            self.highlight_log.clear();
+            if result.done {
+                self.highlight_pending = false;
+            }
+        }
+    }
 
-        let syntax = syntaxes.find_syntax_for_file(path).unwrap().unwrap();
-        let mut parser = syntect::parsing::ParseState::new(syntax);
-        let mut scopes = syntect::parsing::ScopeStack::new();
+    #[inline]
+    pub fn is_highlighting(&self) -> bool {
+        self.highlight_pending
+    }
 
-        let selectors = ScopeSelectors::default();
+    /// Splits the given row into spans of text, each with the scope it should be highlighted as,
+    /// if any. The ranges are byte offsets into the row's content.
+    pub fn row_spans(&self, row: &Row<'_>) -> Vec<(Range<usize>, Option<SourceScope>)> {
+        let row_end = row.start + row.content.len();
+        let scopes = self.scopes.get(row.line_index).map_or(&[][..], Vec::as_slice);
 
-        for (line_index, line) in self.lines.iter().enumerate() {
-            let ops = parser.parse_line(&line.content, syntaxes).unwrap();
-            for (range, op) in syntect::easy::ScopeRangeIterator::new(&ops, &line.content) {
-                scopes.apply(op).unwrap();
-                if range.is_empty() {
-                    continue;
-                }
-                if let Some(scope) = {
-                    if selectors.comment.does_match(scopes.as_slice()).is_some() {
-                        if selectors.doc_comment.does_match(scopes.as_slice()).is_some() {
-                            Some(SourceScope::DocComment)
-                        } else {
-                            Some(SourceScope::Comment)
-                        }
-                    } else if selectors.function.does_match(scopes.as_slice()).is_some() {
-                        Some(SourceScope::Function)
-                    } else if selectors.keyword.does_match(scopes.as_slice()).is_some() {
-                        Some(SourceScope::Keyword)
-                    } else if selectors.types.does_match(scopes.as_slice()).is_some() {
-                        Some(SourceScope::Type)
-                    } else {
-                        None
-                    }
-                } {
-                    self.scopes.push((
-                        line_index,
-                        range,
-                        scope,
-                    ));
-                }
+        let mut spans = Vec::new();
+        let mut offset = row.start;
+        for (range, scope) in scopes {
+            let start = range.start.max(offset);
+            let end = range.end.min(row_end);
+            // Scopes may be out of date with the text until the next parse, so ignore any that
+            // no longer fit it.
+            if start >= end
+                || !row.content.is_char_boundary(start - row.start)
+                || !row.content.is_char_boundary(end - row.start)
+            {
+                continue;
+            }
+            if offset < start {
+                spans.push((offset - row.start..start - row.start, None));
             }
+            spans.push((start - row.start..end - row.start, Some(*scope)));
+            offset = end;
         }
+        if offset < row_end {
+            spans.push((offset - row.start..row_end - row.start, None));
+        }
+
+        spans
     }
 
-    pub fn rows(&self) -> impl DoubleEndedIterator<Item = Row> {
-        let mut num = 0;
-        self.lines.iter()
-            .enumerate()
-            .flat_map(move |(line_index, line)| {
-                if line.content.len() > self.cols {
-                    let mut rows = Vec::with_capacity(3);
-                    let (first, mut last) = line.content.split_at(self.cols);
-                    num += 1;
-                    rows.push(Row {
-                        num,
-                        index: 0,
-                        line_index,
-                        content: first,
-                    });
-                    let mut index = 1;
-                    while last.len() > self.cols {
-                        let (next, then) = line.content.split_at(self.cols);
-                        last = then;
-                        num += 1;
-                        rows.push(Row {
-                            num,
-                            index,
-                            line_index,
-                            content: next,
-                        });
-                        index += 1;
-                    }
-                    num += 1;
-                    rows.push(Row {
-                        num,
+    /// Iterates over the rows of every line, starting from the given line. Lines longer than the
+    /// buffer's column count are wrapped onto multiple rows.
+    pub fn rows_from(&self, first_line: usize) -> impl Iterator<Item = Row<'_>> {
+        let cols = self.wrap_cols();
+        (first_line..self.text.len_lines())
+            .flat_map(move |line_index| {
+                let line = self.text.line_str(line_index);
+                let rows: Vec<(usize, Cow<'_, str>)> = match &line {
+                    Cow::Borrowed(content) => wrap_rows(content, cols)
+                        .into_iter()
+                        .map(|range| (range.start, Cow::Borrowed(&content[range])))
+                        .collect(),
+                    Cow::Owned(content) => wrap_rows(content, cols)
+                        .into_iter()
+                        .map(|range| (range.start, Cow::Owned(content[range].to_string())))
+                        .collect(),
+                };
+
+                rows.into_iter()
+                    .enumerate()
+                    .map(move |(index, (start, content))| Row {
                         index,
                         line_index,
-                        content: last,
-                    });
-                    rows.into_iter()
-                } else {
-                    num += 1;
-                    // std::iter::once(line.content.as_str())
-                    vec![
-                        Row {
-                            num,
-                            index: 0,
-                            line_index,
-                            content: line.content.as_str(),
-                        }
-                    ].into_iter()
-                }
+                        start,
+                        content,
+                    })
             })
     }
 
-    pub fn visible_rows(&self) -> impl Iterator<Item = Row> {
-        self.rows()
-            .skip(self.scroll_y_offset as usize)
+    /// The number of columns that lines are wrapped at.
+    fn wrap_cols(&self) -> usize {
+        // The column count isn't known until the buffer is first rendered.
+        if self.cols == 0 { usize::MAX } else { self.cols }
+    }
+
+    /// Gets the byte ranges of the wrapped rows of the line at the given index.
+    fn line_rows(&self, line_index: usize) -> Vec<Range<usize>> {
+        wrap_rows(&self.text.line_str(line_index), self.wrap_cols())
+    }
+
+    /// Gets the index of the wrapped row that contains the cursor, and the cursor's column in it.
+    pub fn cursor_row_col(&self, cursor: Cursor) -> (usize, usize) {
+        let line = self.text.line_str(cursor.line);
+        let rows = wrap_rows(&line, self.wrap_cols());
+        let row_index = rows.iter()
+            .rposition(|row| row.start <= cursor.index)
+            .unwrap_or(0);
+        let col = line[rows[row_index].start..cursor.index].graphemes(true).count();
+
+        (row_index, col)
+    }
+
+    /// Gets the cursor at the given column of a wrapped row of a line. The row index and column
+    /// are clamped to the line.
+    pub fn cursor_at_row_col(&self, line_index: usize, row_index: usize, col: usize) -> Cursor {
+        let line = self.text.line_str(line_index);
+        let rows = wrap_rows(&line, self.wrap_cols());
+        let row_index = row_index.min(rows.len() - 1);
+        let row = rows[row_index].clone();
+        let is_last_row = row_index + 1 == rows.len();
+
+        let mut boundaries = line[row.clone()].grapheme_indices(true).map(|(i, _)| row.start + i);
+        let index = match boundaries.nth(col) {
+            Some(index) => index,
+            // The end of a wrapped row is the start of the next one, so the cursor can only be
+            // placed there on the last row.
+            None if is_last_row => row.end,
+            None => prev_grapheme_boundary(&line, row.end),
+        };
+
+        Cursor { line: line_index, index }
+    }
+
+    pub fn visible_rows(&self) -> impl Iterator<Item = Row<'_>> {
+        self.rows_from(self.scroll_line)
             .take(self.rows)
     }
 }
 
 impl Buffer {
-    pub fn insert_at(&mut self, mut cursor: Cursor, content: &str) -> Cursor {
-        let mut remaining_split_len = content.len();
-        if remaining_split_len == 0 {
-            return cursor;
+    pub fn search(&self) -> Option<&Search> {
+        self.search.as_ref().map(|state| &state.search)
+    }
+
+    /// Replaces the current search, restarting the count of its matches.
+    pub fn set_search(&mut self, search: Option<Search>) {
+        self.search = search.map(|search| SearchState::new(search, self.version));
+    }
+
+    /// Counts the matches of the current search in up to `max_lines` more lines. Returns whether
+    /// there are lines left to count.
+    pub fn count_search_matches(&mut self, max_lines: usize) -> bool {
+        match &mut self.search {
+            Some(state) => state.count_matches(&self.text, self.version, max_lines),
+            None => false,
+        }
+    }
+
+    /// The number of matches of the current search counted so far, and whether that is all of
+    /// them.
+    pub fn search_count(&self) -> Option<(usize, bool)> {
+        self.search.as_ref().map(SearchState::count)
+    }
+
+    /// Finds the matches of the current search in the line at the given index.
+    pub fn line_matches(&self, line_index: usize) -> Vec<(Cursor, Cursor)> {
+        let Some(search) = self.search() else { return vec![]; };
+        let line = self.text.line_str(line_index);
+
+        search.find_in_line(&line)
+            .map(|range| {
+                (
+                    Cursor { line: line_index, index: range.start },
+                    Cursor { line: line_index, index: range.end },
+                )
+            })
+            .collect()
+    }
+
+    /// Selects the first match of the current search at or after `from`, wrapping around to the
+    /// start of the buffer. Returns whether there was a match.
+    pub fn select_next_match(&mut self, from: Cursor) -> bool {
+        let found = self.search().and_then(|search| search.find_next(&self.text, from));
+        self.select_match(found)
+    }
+
+    /// Selects the last match of the current search before `before`, wrapping around to the end
+    /// of the buffer. Returns whether there was a match.
+    pub fn select_prev_match(&mut self, before: Cursor) -> bool {
+        let found = self.search().and_then(|search| search.find_prev(&self.text, before));
+        self.select_match(found)
+    }
+
+    fn select_match(&mut self, found: Option<(Cursor, Cursor)>) -> bool {
+        let Some((start, end)) = found else { return false; };
+        self.select_range(start, end)
+    }
+
+    /// Selects the text between the two cursors with the primary cursor, removing any others,
+    /// and scrolls it into view. Returns false, selecting nothing, if either cursor isn't a valid
+    /// position in the text.
+    pub fn select_range(&mut self, start: Cursor, end: Cursor) -> bool {
+        let is_valid = |cursor: Cursor| {
+            cursor.line < self.text.len_lines()
+                && self.text.line_str(cursor.line).is_char_boundary(cursor.index)
+        };
+        if !is_valid(start) || !is_valid(end) {
+            return false;
+        }
+
+        self.history.seal();
+        self.other_cursors.clear();
+        self.selection = if start == end { Selection::None } else { Selection::Normal(start) };
+        self.cursor = end;
+        self.preferred_col = None;
+        self.scroll_to_cursor();
+
+        true
+    }
+
+    /// Finds the matches of the current search in the line at the given index, with what each
+    /// should be replaced with. With `within`, only matches inside one of those ranges are found.
+    fn line_replacements(
+        &self,
+        line_index: usize,
+        replace: &Replace,
+        within: Option<&[(Cursor, Cursor)]>,
+    ) -> Vec<(Range<usize>, String)> {
+        let Some(search) = self.search() else { return vec![]; };
+        let line = self.text.line_str(line_index);
+        let is_within = |range: &Range<usize>| within.is_none_or(|within| {
+            let start = Cursor { line: line_index, index: range.start };
+            let end = Cursor { line: line_index, index: range.end };
+            within.iter().any(|(s, e)| *s <= start && end <= *e)
+        });
+
+        search.find_in_line(&line)
+            .filter(is_within)
+            .map(|range| (range.clone(), search.replacement(&line, range, replace)))
+            .collect()
+    }
+
+    /// The indices of the lines that a replacement could change. These are the lines of the
+    /// selections when `in_selection` is set, or every line otherwise.
+    fn replace_lines(&self, in_selection: bool) -> Vec<usize> {
+        if !in_selection {
+            return (0..self.text.len_lines()).collect();
+        }
+
+        let mut lines: Vec<usize> = self.all_selection_bounds().into_iter()
+            .flat_map(|(start, end)| start.line..=end.line)
+            .collect();
+        lines.dedup();
+
+        lines
+    }
+
+    /// Previews the first `max_lines` lines that [`Buffer::replace_all`] would change.
+    pub fn replace_preview(
+        &self,
+        replace: &Replace,
+        in_selection: bool,
+        max_lines: usize,
+    ) -> Vec<ReplacePreview> {
+        let within = in_selection.then(|| self.all_selection_bounds());
+        self.replace_lines(in_selection).into_iter()
+            .filter_map(|line_index| {
+                let replacements = self.line_replacements(line_index, replace, within.as_deref());
+                if replacements.is_empty() {
+                    return None;
+                }
+                let before = self.text.line_str(line_index).into_owned();
+                let mut after = before.clone();
+                for (range, text) in replacements.into_iter().rev() {
+                    after.replace_range(range, &text);
+                }
+
+                Some(ReplacePreview { line_index, before, after })
+            })
+            .take(max_lines)
+            .collect()
+    }
+
+    /// Replaces the selected match of the current search, then selects the next match. If no
+    /// match is selected, the next match is only selected. Returns whether there was a match.
+    pub fn replace_next(&mut self, replace: &Replace) -> bool {
+        if self.read_only {
+            return self.select_next_match(self.cursor);
+        }
+        let selected = self.selection_bounds().and_then(|(start, end)| {
+            if start.line != end.line {
+                return None;
+            }
+            self.line_replacements(start.line, replace, None).into_iter()
+                .find(|(range, _)| range.start == start.index && range.end == end.index)
+                .map(|(_, text)| (start, end, text))
+        });
+        let Some((start, end, text)) = selected else {
+            return self.select_next_match(self.cursor);
+        };
+
+        self.history.seal();
+        self.history.begin(self.cursor, self.selection);
+        self.delete_range(start, end);
+        let end = self.insert_at(start, &text);
+        self.selection = Selection::None;
+        self.cursor = end;
+        self.history.commit(self.cursor);
+        self.history.seal();
+
+        self.select_next_match(end);
+
+        true
+    }
+
+    /// Replaces every match of the current search, or only those inside the selections when
+    /// `in_selection` is set, as a single undoable transaction. Returns the number of matches
+    /// that were replaced.
+    pub fn replace_all(&mut self, replace: &Replace, in_selection: bool) -> usize {
+        if self.read_only {
+            return 0;
+        }
+        let within = in_selection.then(|| self.all_selection_bounds());
+        let replacements: Vec<(Cursor, Cursor, String)> = self.replace_lines(in_selection)
+            .into_iter()
+            .flat_map(|line_index| {
+                self.line_replacements(line_index, replace, within.as_deref()).into_iter()
+                    .map(move |(range, text)| (
+                        Cursor { line: line_index, index: range.start },
+                        Cursor { line: line_index, index: range.end },
+                        text,
+                    ))
+            })
+            .collect();
+        if replacements.is_empty() {
+            return 0;
         }
 
-        // TODO: Ensure that the line exists.
-        let line: &mut Line = &mut self.lines[cursor.line];
-        let insert_line = cursor.line + 1;
+        self.history.seal();
+        self.history.begin(self.cursor, self.selection);
+        // Cursors at the start of a match stay before its replacement, rather than being pushed
+        // after it like they would be by an insertion.
+        let primary = CursorState {
+            cursor: self.cursor,
+            selection: self.selection,
+            preferred_col: self.preferred_col,
+        };
+        let mut cursors: Vec<CursorState> = std::iter::once(primary)
+            .chain(self.other_cursors.iter().copied())
+            .collect();
+        // Replace from the end, so that the positions of the matches before are unchanged.
+        for (start, end, text) in replacements.iter().rev() {
+            self.delete_range(*start, *end);
+            let new_end = self.insert_at(*start, text);
+            for state in &mut cursors {
+                state.map(|cursor| {
+                    if cursor <= *start {
+                        cursor
+                    } else {
+                        cursor.after_delete(*start, *end).after_insert(*start, new_end)
+                    }
+                });
+            }
+        }
+        self.cursor = cursors[0].cursor;
+        self.selection = cursors[0].selection;
+        self.other_cursors = cursors.split_off(1);
+        self.merge_cursors();
+        self.history.commit(self.cursor);
+        self.history.seal();
+
+        replacements.len()
+    }
+
+    /// Scrolls the cursor into view, centering it vertically if it is off screen.
+    pub fn scroll_to_cursor(&mut self) {
+        let (cursor_row, _) = self.cursor_row_col(self.cursor);
+        if self.cursor.line >= self.scroll_line {
+            // The rows above the cursor's row, from the top of the screen.
+            let mut rows_above = cursor_row;
+            for line_index in self.scroll_line..self.cursor.line {
+                if rows_above >= self.rows {
+                    break;
+                }
+                rows_above += self.line_rows(line_index).len();
+            }
+            if rows_above < self.rows {
+                return;
+            }
+        }
 
-        let after: Line = line.split_off(cursor.index);
-        let after_len = after.content.len();
+        // Only whole lines can be scrolled past, so walk back from the cursor's line until about
+        // half of the screen is above it.
+        let mut line_index = self.cursor.line;
+        let mut rows_above = cursor_row;
+        while line_index > 0 {
+            let rows = self.line_rows(line_index - 1).len();
+            if rows_above + rows > self.rows / 2 {
+                break;
+            }
+            rows_above += rows;
+            line_index -= 1;
+        }
+        self.scroll_line = line_index;
+    }
+}
 
-        // Append the inserted text, line by line
-        // we want to see a blank entry if the string ends with a newline
-        // TODO: adjust this to get line ending from data?
-        let addendum = std::iter::once("").filter(|_| content.ends_with('\n'));
-        let mut lines_iter = content.split_inclusive('\n').chain(addendum);
-        if let Some(content_line) = lines_iter.next() {
-            remaining_split_len -= content_line.len();
-            line.append(Line {
-                content: content_line.to_string(),
+impl Buffer {
+    /// Inserts the given content at the cursor, returning the position at the end of the inserted
+    /// content. The insertion is recorded in the buffer's undo history.
+    pub fn insert_at(&mut self, cursor: Cursor, content: &str) -> Cursor {
+        let end = self.apply_insert(cursor, content);
+        if end != cursor {
+            self.history.record(Edit::Insert {
+                start: cursor,
+                end,
+                text: content.to_string(),
             });
-        } else {
-            panic!("str::lines() did not yield any elements");
-        }
-        if let Some(content_line) = lines_iter.next_back() {
-            remaining_split_len -= content_line.len();
-            let mut tmp = Line {
-                content: content_line
-                    .strip_suffix(char::is_control)
-                    .unwrap_or(content_line)
-                    .to_string(),
-            };
-            tmp.append(after);
-            self.lines.insert(insert_line, tmp);
-            cursor.line += 1;
-        } else {
-            line.append(after);
-        }
-        for content_line in lines_iter.rev() {
-            remaining_split_len -= content_line.len();
-            let tmp = Line {
-                content: content_line
-                    .strip_suffix(char::is_control)
-                    .unwrap_or(content_line)
-                    .to_string(),
-            };
-            self.lines.insert(insert_line, tmp);
-            cursor.line += 1;
         }
 
-        assert_eq!(remaining_split_len, 0);
+        end
+    }
+
+    fn apply_insert(&mut self, cursor: Cursor, content: &str) -> Cursor {
+        if content.is_empty() {
+            return cursor;
+        }
+        let end = self.text.insert(cursor, content);
+        self.inserted(cursor, end)
+    }
+
+    /// Puts back text that was deleted from the buffer, exactly as it was.
+    fn apply_restore(&mut self, cursor: Cursor, text: &str) -> Cursor {
+        if text.is_empty() {
+            return cursor;
+        }
+        let end = self.text.insert_verbatim(cursor, text);
+        self.inserted(cursor, end)
+    }
 
-        cursor.index = self.lines[cursor.line].content.len() - after_len;
+    /// Keeps the other cursors and the line scopes in line with text inserted between the two
+    /// cursors.
+    fn inserted(&mut self, cursor: Cursor, end: Cursor) -> Cursor {
+        self.dirty = true;
+        for other in &mut self.other_cursors {
+            other.map(|other| other.after_insert(cursor, end));
+        }
+        self.record_line_edit(LineEdit {
+            line: cursor.line,
+            old_end: cursor.line,
+            new_end: end.line,
+        });
 
-        // TODO: Optimize the parsing sequence before re-parsing so frequently.
-        // self.needs_reparse = true;
+        end
+    }
 
-        cursor
+    /// Adds the given content to the end of the buffer, without recording it in the undo history.
+    pub fn append(&mut self, content: &str) {
+        let last_line = self.text.len_lines() - 1;
+        let end = Cursor { line: last_line, index: self.text.line_len(last_line) };
+        self.apply_insert(end, content);
     }
 
     pub fn insert_string(&mut self, content: &str) {
@@ -665,36 +3195,69 @@ impl Buffer {
         if let Selection::None = &self.selection {
             self.selection = Selection::Normal(self.cursor);
         }
+        for other in &mut self.other_cursors {
+            if let Selection::None = &other.selection {
+                other.selection = Selection::Normal(other.cursor);
+            }
+        }
+    }
+
+    /// Clears the selection of every cursor.
+    pub fn clear_selection(&mut self) {
+        self.selection = Selection::None;
+        for other in &mut self.other_cursors {
+            other.selection = Selection::None;
+        }
     }
 
     pub fn selection_bounds(&self) -> Option<(Cursor, Cursor)> {
-        match self.selection {
+        self.selection_bounds_of(self.cursor, self.selection)
+    }
+
+    /// Gets the bounds of the selection of every cursor that has one, in the order they appear
+    /// in the text.
+    pub fn all_selection_bounds(&self) -> Vec<(Cursor, Cursor)> {
+        let mut bounds: Vec<(Cursor, Cursor)> = self.cursors()
+            .filter_map(|(cursor, selection)| self.selection_bounds_of(cursor, selection))
+            .collect();
+        bounds.sort();
+
+        bounds
+    }
+
+    fn selection_bounds_of(&self, cursor: Cursor, selection: Selection) -> Option<(Cursor, Cursor)> {
+        match selection {
             Selection::None => None,
             Selection::Normal(select) => {
-                match select.line.cmp(&self.cursor.line) {
-                    std::cmp::Ordering::Greater => Some((self.cursor, select)),
-                    std::cmp::Ordering::Less => Some((select, self.cursor)),
+                match select.line.cmp(&cursor.line) {
+                    std::cmp::Ordering::Greater => Some((cursor, select)),
+                    std::cmp::Ordering::Less => Some((select, cursor)),
                     std::cmp::Ordering::Equal => {
                         /* select.line == cursor.line */
-                        if select.index < self.cursor.index {
-                            Some((select, self.cursor))
+                        if select.index < cursor.index {
+                            Some((select, cursor))
                         } else {
                             /* select.index >= cursor.index */
-                            Some((self.cursor, select))
+                            Some((cursor, select))
                         }
                     }
                 }
             }
             Selection::Line(select) => {
-                let start_line = std::cmp::min(select.line, self.cursor.line);
-                let end_line = std::cmp::max(select.line, self.cursor.line);
-                let end_index = self.lines[end_line].content.len();
-                Some((
-                    Cursor { line: start_line, index: 0 },
-                    Cursor { line: end_line, index: end_index },
-                ))
+                let start_line = std::cmp::min(select.line, cursor.line);
+                let end_line = std::cmp::max(select.line, cursor.line);
+                // Include the line break after the last line, so that whole lines are deleted.
+                let end = if end_line + 1 < self.text.len_lines() {
+                    Cursor { line: end_line + 1, index: 0 }
+                } else {
+                    Cursor { line: end_line, index: self.text.line_len(end_line) }
+                };
+                Some((Cursor { line: start_line, index: 0 }, end))
+            }
+            Selection::Word(select) => {
+                let (start, end) = if select <= cursor { (select, cursor) } else { (cursor, select) };
+                Some((self.word_start(start), self.word_end(end)))
             }
-            Selection::Word(_) => todo!(),
         }
     }
 
@@ -712,51 +3275,391 @@ impl Buffer {
         true
     }
 
+    /// Deletes the text between the two cursors. The deletion is recorded in the buffer's undo
+    /// history.
     pub fn delete_range(&mut self, start: Cursor, end: Cursor) {
-        // Delete from the last line.
-        let end_line_opt = if end.line > start.line {
-            let after = self.lines[end.line].split_off(end.index);
-            let _removed = self.lines.remove(end.line);
+        if start == end {
+            return;
+        }
+        let text = self.text_range(start, end);
+        self.apply_delete(start, end);
+        self.history.record(Edit::Delete { start, end, text });
+    }
 
-            Some(after)
-        } else {
-            None
+    /// Gets the text between the two cursors, with lines separated by `\n`.
+    pub fn text_range(&self, start: Cursor, end: Cursor) -> String {
+        self.text.slice(start, end)
+    }
+
+    /// Gets the selected text, with lines separated by the buffer's line ending. The text of
+    /// each selection is put on its own line.
+    pub fn selected_text(&self) -> Option<String> {
+        let bounds = self.all_selection_bounds();
+        if bounds.is_empty() {
+            return None;
+        }
+        let text = bounds.into_iter()
+            .map(|(start, end)| self.text_range(start, end))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Some(match self.line_ending {
+            LineEnding::Lf => text,
+            LineEnding::CrLf => text.replace('\n', "\r\n"),
+        })
+    }
+
+    /// Keeps the line scopes in line with an edit, and queues it to be sent to the highlight worker.
+    fn record_line_edit(&mut self, edit: LineEdit) {
+        self.version += 1;
+        self.edited_at = Some(std::time::Instant::now());
+        edit.splice(&mut self.scopes, vec![]);
+        self.highlight_edits.push(edit);
+        self.highlight_log.push((self.version, edit));
+    }
+
+    fn apply_delete(&mut self, start: Cursor, end: Cursor) {
+        self.text.remove(start, end);
+        if start != end {
+            self.dirty = true;
+        }
+        for other in &mut self.other_cursors {
+            other.map(|other| other.after_delete(start, end));
+        }
+        self.record_line_edit(LineEdit {
+            line: start.line,
+            old_end: end.line,
+            new_end: start.line,
+        });
+    }
+
+    /// Reverts the last transaction in the undo history, restoring the cursor and selection to
+    /// where they were before it was made.
+    pub fn undo(&mut self) -> bool {
+        let mut history = std::mem::take(&mut self.history);
+        let Some(transaction) = history.undo() else {
+            self.history = history;
+            return false;
         };
 
-        for line_i in (start.line + 1..end.line).rev() {
-            let _removed = self.lines.remove(line_i);
+        for edit in transaction.edits.iter().rev() {
+            match edit {
+                Edit::Insert { start, end, .. } => {
+                    self.apply_delete(*start, *end);
+                }
+                Edit::Delete { start, text, .. } => {
+                    self.apply_restore(*start, text);
+                }
+            }
         }
+        self.cursor = transaction.cursor_before;
+        self.selection = transaction.selection_before;
+        self.other_cursors.clear();
+        self.history = history;
 
-        // Delete from the first line.
-        {
-            // Get part after selection if start line is also end line
-            let after_opt = if start.line == end.line {
-                Some(self.lines[start.line].split_off(end.index))
+        true
+    }
+
+    /// Re-applies the last transaction that was undone.
+    pub fn redo(&mut self) -> bool {
+        let mut history = std::mem::take(&mut self.history);
+        let Some(transaction) = history.redo() else {
+            self.history = history;
+            return false;
+        };
+
+        for edit in transaction.edits.iter() {
+            match edit {
+                Edit::Insert { start, text, .. } => {
+                    self.apply_insert(*start, text);
+                }
+                Edit::Delete { start, end, .. } => {
+                    self.apply_delete(*start, *end);
+                }
+            }
+        }
+        self.cursor = transaction.cursor_after;
+        self.selection = Selection::None;
+        self.other_cursors.clear();
+        self.history = history;
+
+        true
+    }
+
+    /// Performs the given action, recording any edits it makes as a single undoable transaction.
+    /// Copied text is written to, and pasted text read from, the given clipboard.
+    pub fn perform_action(&mut self, action: EditAction, clipboard: &mut dyn Clipboard) {
+        if self.read_only && action.edits_text() {
+            return;
+        }
+        match action {
+            EditAction::Undo => {
+                self.undo();
+            }
+            EditAction::Redo => {
+                self.redo();
+            }
+            EditAction::Copy | EditAction::Cut | EditAction::Paste => {
+                self.history.begin(self.cursor, self.selection);
+                self.apply_clipboard_action(action, clipboard);
+                self.merge_cursors();
+                self.history.commit(self.cursor);
+            }
+            // These act on the buffer as a whole, rather than at each cursor.
+            EditAction::Click(_)
+            | EditAction::Drag(_)
+            | EditAction::DoubleClick(_)
+            | EditAction::TripleClick(_)
+            | EditAction::SelectAll
+            | EditAction::ScrollUp
+            | EditAction::ScrollDown
+            | EditAction::AddCursorAbove
+            | EditAction::AddCursorBelow
+            | EditAction::AddNextOccurrence
+            | EditAction::CollapseCursors
+            | EditAction::FindNext
+            | EditAction::FindPrevious
+            | EditAction::NextHunk
+            | EditAction::PreviousHunk
+            | EditAction::RevertHunk => {
+                self.history.begin(self.cursor, self.selection);
+                self.apply_action(action);
+                self.merge_cursors();
+                self.history.commit(self.cursor);
+            }
+            action => {
+                self.history.begin(self.cursor, self.selection);
+                self.for_each_cursor(|buffer, _| {
+                    if !matches!(action, EditAction::MoveUp | EditAction::MoveDown) {
+                        buffer.preferred_col = None;
+                    }
+                    buffer.apply_action(action);
+                });
+                self.merge_cursors();
+                self.history.commit(self.cursor);
+            }
+        }
+        self.snap_cursors_to_graphemes();
+    }
+
+    /// Moves any cursor or selection anchor that is inside a grapheme to the end of it. Edits can
+    /// join the graphemes on either side of them, such as by removing the character between a
+    /// zero width joiner and an emoji, or by typing a combining mark before another cursor.
+    fn snap_cursors_to_graphemes(&mut self) {
+        let snap = |text: &TextBuffer, cursor: Cursor| {
+            let line = text.line_str(cursor.line);
+            if is_grapheme_boundary(&line, cursor.index) {
+                cursor
             } else {
-                None
-            };
+                Cursor { line: cursor.line, index: next_grapheme_boundary(&line, cursor.index) }
+            }
+        };
+        let mut snapped = false;
+        let mut primary = CursorState {
+            cursor: self.cursor,
+            selection: self.selection,
+            preferred_col: self.preferred_col,
+        };
+        for state in std::iter::once(&mut primary).chain(&mut self.other_cursors) {
+            let before = (state.cursor, state.selection);
+            state.map(|cursor| snap(&self.text, cursor));
+            snapped |= (state.cursor, state.selection) != before;
+        }
+        self.cursor = primary.cursor;
+        self.selection = primary.selection;
+        if snapped {
+            self.merge_cursors();
+        }
+    }
+
+    /// Iterates over every cursor and its selection, starting with the primary cursor.
+    pub fn cursors(&self) -> impl Iterator<Item = (Cursor, Selection)> + '_ {
+        std::iter::once((self.cursor, self.selection))
+            .chain(self.other_cursors.iter().map(|other| (other.cursor, other.selection)))
+    }
+
+    /// Calls `f` once for each cursor, with that cursor swapped in as the primary one, in the order
+    /// the cursors appear in the text. `f` is also given the index of the cursor in that order.
+    ///
+    /// The cursors that aren't swapped in are moved along with any edits that `f` makes.
+    fn for_each_cursor(&mut self, mut f: impl FnMut(&mut Self, usize)) {
+        let mut order: Vec<Option<usize>> = std::iter::once(None)
+            .chain((0..self.other_cursors.len()).map(Some))
+            .collect();
+        order.sort_by_key(|index| match index {
+            Some(index) => self.other_cursors[*index].cursor,
+            None => self.cursor,
+        });
+
+        for (n, index) in order.into_iter().enumerate() {
+            match index {
+                Some(index) => {
+                    self.swap_cursor(index);
+                    f(self, n);
+                    self.swap_cursor(index);
+                }
+                None => f(self, n),
+            }
+        }
+    }
+
+    fn swap_cursor(&mut self, index: usize) {
+        let other = &mut self.other_cursors[index];
+        std::mem::swap(&mut self.cursor, &mut other.cursor);
+        std::mem::swap(&mut self.selection, &mut other.selection);
+        std::mem::swap(&mut self.preferred_col, &mut other.preferred_col);
+    }
 
-            let _removed = self.lines[start.line].split_off(start.index);
+    /// Removes any cursors that have run into another cursor or its selection. The primary cursor
+    /// is always kept.
+    fn merge_cursors(&mut self) {
+        if self.other_cursors.is_empty() {
+            return;
+        }
+
+        let span = |cursor, selection| {
+            self.selection_bounds_of(cursor, selection).unwrap_or((cursor, cursor))
+        };
+        let mut kept = vec![(self.cursor, span(self.cursor, self.selection))];
+        let mut others = Vec::with_capacity(self.other_cursors.len());
+        for other in &self.other_cursors {
+            let (start, end) = span(other.cursor, other.selection);
+            let collides = kept.iter().any(|&(cursor, (s, e))| {
+                cursor == other.cursor || (start == s && end == e) || (start < e && s < end)
+            });
+            if !collides {
+                kept.push((other.cursor, (start, end)));
+                others.push(*other);
+            }
+        }
+        others.sort_by_key(|other| other.cursor);
+        self.other_cursors = others;
+    }
 
-            // Re-add part of line after the range.
-            if let Some(after) = after_opt {
-                self.lines[start.line].append(after);
+    fn apply_clipboard_action(&mut self, action: EditAction, clipboard: &mut dyn Clipboard) {
+        match action {
+            EditAction::Copy => {
+                if let Some(text) = self.selected_text() {
+                    clipboard.write(text);
+                }
+            }
+            EditAction::Cut => {
+                if let Some(text) = self.selected_text() {
+                    clipboard.write(text);
+                    self.for_each_cursor(|buffer, _| {
+                        buffer.delete_selection();
+                    });
+                }
             }
-            // Re-add valid parts of end line
-            if let Some(end_line) = end_line_opt {
-                self.lines[start.line].append(end_line);
+            EditAction::Paste => {
+                let Some(text) = clipboard.read() else { return; };
+                let text = text.replace("\r\n", "\n");
+                // Pasting is never merged into the characters typed before it.
+                self.history.seal();
+
+                // Text copied from the same number of cursors is split back up between them.
+                let lines: Vec<&str> = text.split('\n').collect();
+                let split = !self.other_cursors.is_empty()
+                    && lines.len() == self.other_cursors.len() + 1;
+                self.for_each_cursor(|buffer, n| {
+                    buffer.insert_string(if split { lines[n] } else { &text });
+                });
             }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the cursor on the row above the given cursor, at the given column.
+    fn row_above(&self, cursor: Cursor, col: usize) -> Option<Cursor> {
+        let (row_index, _) = self.cursor_row_col(cursor);
+        if row_index > 0 {
+            Some(self.cursor_at_row_col(cursor.line, row_index - 1, col))
+        } else if cursor.line > 0 {
+            // Move to the last row of the previous line.
+            Some(self.cursor_at_row_col(cursor.line - 1, usize::MAX, col))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the cursor on the row below the given cursor, at the given column.
+    fn row_below(&self, cursor: Cursor, col: usize) -> Option<Cursor> {
+        let (row_index, _) = self.cursor_row_col(cursor);
+        let row_count = self.line_rows(cursor.line).len();
+        if row_index + 1 < row_count {
+            Some(self.cursor_at_row_col(cursor.line, row_index + 1, col))
+        } else if cursor.line + 1 < self.text.len_lines() {
+            Some(self.cursor_at_row_col(cursor.line + 1, 0, col))
+        } else {
+            None
         }
+    }
+
+    /// Gets the bounds of the word that the cursor is in or touching, if any.
+    fn word_at(&self, cursor: Cursor) -> Option<(Cursor, Cursor)> {
+        let line = self.text.line_str(cursor.line);
+        let (start, word) = line.unicode_word_indices()
+            .find(|(start, word)| *start <= cursor.index && cursor.index <= start + word.len())?;
+
+        Some((
+            Cursor { line: cursor.line, index: start },
+            Cursor { line: cursor.line, index: start + word.len() },
+        ))
+    }
 
-        // TODO: Optimize the parsing sequence before re-parsing so frequently.
-        // self.needs_reparse = true;
+    /// Gets the cursor at the given column and row of the visible text. Rows past the end of the
+    /// text are clamped to the last one.
+    fn cursor_at_cell(&self, (x, y): (u16, u16)) -> Option<Cursor> {
+        self.visible_rows()
+            .take(y as usize + 1)
+            .last()
+            .map(|row| Cursor {
+                line: row.line_index,
+                index: row.index_at_column(x as usize),
+            })
+    }
+
+    /// Gets the start of the word that contains the cursor, or the cursor itself if it isn't in
+    /// a word.
+    fn word_start(&self, cursor: Cursor) -> Cursor {
+        let line = self.text.line_str(cursor.line);
+        let start = line.unicode_word_indices()
+            .find(|(start, word)| *start <= cursor.index && cursor.index < start + word.len())
+            .map_or(cursor.index, |(start, _)| start);
+
+        Cursor { line: cursor.line, index: start }
+    }
+
+    /// Gets the end of the word that contains or touches the cursor, or the cursor itself if
+    /// there isn't one.
+    fn word_end(&self, cursor: Cursor) -> Cursor {
+        let line = self.text.line_str(cursor.line);
+        let end = line.unicode_word_indices()
+            .find(|(start, word)| *start <= cursor.index && cursor.index <= start + word.len())
+            .map_or(cursor.index, |(start, word)| start + word.len());
+
+        Cursor { line: cursor.line, index: end }
+    }
+
+    /// Selects the word under the cursor, so that extending the selection extends it by whole
+    /// words.
+    fn select_word(&mut self) {
+        if let Some((start, end)) = self.word_at(self.cursor) {
+            self.selection = Selection::Word(start);
+            self.cursor = end;
+        }
     }
 
-    pub fn perform_action(&mut self, action: EditAction) {
+    fn apply_action(&mut self, action: EditAction) {
         match action {
+            EditAction::Undo
+            | EditAction::Redo
+            | EditAction::Copy
+            | EditAction::Cut
+            | EditAction::Paste => unreachable!(),
             EditAction::Insert(ch) => {
                 if ch == '\n' {
-                    self.perform_action(EditAction::NewLine);
+                    self.apply_action(EditAction::NewLine);
                 } else {
                     let mut str_buf = [0u8; 8];
                     let str_ref = ch.encode_utf8(&mut str_buf);
@@ -779,17 +3682,13 @@ impl Buffer {
                     let end = self.cursor;
 
                     if self.cursor.index > 0 {
-                        // Move cursor to previous character index.
-                        self.cursor.index = {
-                            self.lines[self.cursor.line].content[..self.cursor.index]
-                                .char_indices()
-                                .next_back()
-                                .map_or(0, |(i, _)| i)
-                        };
+                        // Move cursor to previous grapheme boundary.
+                        let line = self.text.line_str(self.cursor.line);
+                        self.cursor.index = prev_grapheme_boundary(&line, self.cursor.index);
                     } else if self.cursor.line > 0 {
                         // Move cursor to previous line.
                         self.cursor.line -= 1;
-                        self.cursor.index = self.lines[self.cursor.line].content.len();
+                        self.cursor.index = self.text.line_len(self.cursor.line);
                     }
 
                     if self.cursor != end {
@@ -801,178 +3700,163 @@ impl Buffer {
                 if self.delete_selection() {
                     // Deleted selection.
                 } else {
-                    let mut start = self.cursor;
+                    let start = self.cursor;
                     let mut end = self.cursor;
 
-                    if start.index < self.lines[start.line].content.len() {
-                        let line = &self.lines[start.line];
-
-                        let range_opt = line
-                            .content
-                            .grapheme_indices(true)
-                            .take_while(|(i, _)| *i <= start.index)
-                            .last()
-                            .map(|(i, c)| i..(i + c.len()));
-
-                        if let Some(range) = range_opt {
-                            start.index = range.start;
-                            end.index = range.end;
-                        }
-                    } else if start.line + 1 < self.lines.len() {
+                    if start.index < self.text.line_len(start.line) {
+                        let line = self.text.line_str(start.line);
+                        end.index = next_grapheme_boundary(&line, start.index);
+                    } else if start.line + 1 < self.text.len_lines() {
                         end.line += 1;
                         end.index = 0;
                     }
 
                     if start != end {
-                        self.cursor = start;
                         self.delete_range(start, end);
                     }
                 }
             }
-            EditAction::Click((x, y)) => {
-                let mut new_line_index = self.cursor.line;
-                let mut new_index = self.cursor.index;
-
-                if let Some((_, row)) = self.visible_rows()
-                    .enumerate()
-                    .find(|(i, _)| *i == y as usize)
-                {
-                    new_line_index = row.line_index;
-                    let addendum = if x as usize > row.content.len() {
-                        row.content.len()
-                    } else {
-                        x as usize
-                    };
-                    new_index = (row.index * self.cols) + addendum;
+            EditAction::Click(cell) => {
+                if let Some(cursor) = self.cursor_at_cell(cell) {
+                    self.cursor = cursor;
+                    self.selection = Selection::None;
+                    self.other_cursors.clear();
                 }
-
-                self.cursor.line = new_line_index;
-                self.cursor.index = new_index;
+            }
+            EditAction::Drag(cell) => {
+                if let Some(cursor) = self.cursor_at_cell(cell) {
+                    self.cursor = cursor;
+                }
+            }
+            EditAction::DoubleClick(position) => {
+                self.apply_action(EditAction::Click(position));
+                self.select_word();
+            }
+            EditAction::TripleClick(position) => {
+                self.apply_action(EditAction::Click(position));
+                self.selection = Selection::Line(self.cursor);
+            }
+            EditAction::SelectWord => {
+                if !matches!(self.selection, Selection::Word(_)) {
+                    self.select_word();
+                }
+            }
+            EditAction::SelectLine => {
+                if let Selection::Line(_) = self.selection {
+                    // Extend the selection by a line.
+                    if self.cursor.line + 1 < self.text.len_lines() {
+                        self.cursor = Cursor { line: self.cursor.line + 1, index: 0 };
+                    }
+                } else {
+                    self.selection = Selection::Line(self.cursor);
+                }
+            }
+            EditAction::SelectAll => {
+                let last_line = self.text.len_lines() - 1;
+                self.other_cursors.clear();
+                self.selection = Selection::Normal(Cursor { line: 0, index: 0 });
+                self.cursor = Cursor { line: last_line, index: self.text.line_len(last_line) };
             }
             EditAction::MoveLeft => {
-                let line = self.lines.get(self.cursor.line).unwrap();
                 if self.cursor.index > 0 {
-                    let mut prev_index = 0;
-                    for (i, _c) in line.content.chars().enumerate() {
-                        if i < self.cursor.index {
-                            prev_index = i;
-                        } else {
-                            break;
-                        }
-                    }
-
-                    self.cursor.index = prev_index;
+                    let line = self.text.line_str(self.cursor.line);
+                    self.cursor.index = prev_grapheme_boundary(&line, self.cursor.index);
                 } else if self.cursor.line > 0 {
                     self.cursor.line -= 1;
-                    self.cursor.index = self.lines.get(self.cursor.line).unwrap().content.len();
+                    self.cursor.index = self.text.line_len(self.cursor.line);
                 }
             }
             EditAction::MoveRight => {
-                let line = self.lines.get(self.cursor.line).unwrap();
-                if self.cursor.index < line.content.len() {
-                    for (i, _c) in line.content.chars().enumerate() {
-                        if i == self.cursor.index {
-                            self.cursor.index += 1; // c.len()
-                            break;
-                        }
-                    }
-                } else if self.cursor.line + 1 < self.lines.len() {
+                let line = self.text.line_str(self.cursor.line);
+                if self.cursor.index < line.len() {
+                    self.cursor.index = next_grapheme_boundary(&line, self.cursor.index);
+                } else if self.cursor.line + 1 < self.text.len_lines() {
                     self.cursor.line += 1;
                     self.cursor.index = 0;
                 }
             }
             EditAction::MoveUp => {
-                let line = self.lines.get(self.cursor.line).unwrap();
-                if line.content.len() > self.cols {
-                    // FIXME: This is likely a horribly inefficient way to do this.
-
-                    let row_count = (line.content.len() as f32 / self.cols as f32)
-                        .ceil() as usize;
-                    let mut row_index = 0;
-                    for i in 0..row_count {
-                        if (i * self.cols) <= self.cursor.index {
-                            row_index = i;
-                        } else {
-                            break;
-                        }
-                    }
-                    if row_index > 0 {
-                        let row_offset = self.cursor.index % self.cols;
-                        self.cursor.index = ((row_index - 1) * self.cols)
-                            + row_offset;
-                    } else {
-                        if self.cursor.line > 0 {
-                            self.cursor.line -= 1;
-                            let line = self.lines.get(self.cursor.line).unwrap();
-                            if line.content.len() < self.cursor.index {
-                                self.cursor.index = line.content.len();
-                            }
-                        }
-                    }
-                } else {
-                    if self.cursor.line > 0 {
-                        self.cursor.line -= 1;
-                        let line = self.lines.get(self.cursor.line).unwrap();
-                        let row_count = (line.content.len() as f32 / self.cols as f32)
-                            .ceil() as usize;
-                        if row_count > 1 {
-                            let row_len = line.content.len() % self.cols;
-                            let row_offset = self.cursor.index;
-                            if row_len < row_offset {
-                                self.cursor.index = line.content.len();
-                            } else {
-                                self.cursor.index = ((row_count - 1) * self.cols)
-                                    + row_offset;
-                            }
-                        } else {
-                            if line.content.len() < self.cursor.index {
-                                self.cursor.index = line.content.len();
-                            }
-                        }
-                    }
+                let (_, col) = self.cursor_row_col(self.cursor);
+                let col = *self.preferred_col.get_or_insert(col);
+                if let Some(cursor) = self.row_above(self.cursor, col) {
+                    self.cursor = cursor;
                 }
             }
             EditAction::MoveDown => {
-                let line = self.lines.get(self.cursor.line).unwrap();
-                if line.content.len() > self.cols {
-                    let row_count = (line.content.len() as f32 / self.cols as f32)
-                        .ceil() as usize;
-                    let mut row_index = 0;
-                    for i in 0..row_count {
-                        if (i * self.cols) <= self.cursor.index {
-                            row_index = i;
-                        } else {
-                            break;
-                        }
-                    }
-                    if row_index + 1 < row_count {
-                        let row_offset = self.cursor.index % self.cols;
-                        self.cursor.index = ((row_index + 1) * self.cols)
-                            + row_offset;
-                    } else {
-                        if self.cursor.line + 1 < self.lines.len() {
-                            self.cursor.line += 1;
-                            let line = self.lines.get(self.cursor.line).unwrap();
-                            if line.content.len() < self.cursor.index {
-                                self.cursor.index = line.content.len();
-                            }
-                        }
+                let (_, col) = self.cursor_row_col(self.cursor);
+                let col = *self.preferred_col.get_or_insert(col);
+                if let Some(cursor) = self.row_below(self.cursor, col) {
+                    self.cursor = cursor;
+                }
+            }
+            EditAction::AddCursorAbove | EditAction::AddCursorBelow => {
+                let above = action == EditAction::AddCursorAbove;
+                let cursors = self.cursors().map(|(cursor, _)| cursor);
+                let Some(edge) = (if above { cursors.min() } else { cursors.max() }) else {
+                    return;
+                };
+                let (_, col) = self.cursor_row_col(edge);
+                let col = self.preferred_col.unwrap_or(col);
+                let added = if above { self.row_above(edge, col) } else { self.row_below(edge, col) };
+                if let Some(cursor) = added {
+                    self.other_cursors.push(CursorState {
+                        cursor,
+                        selection: Selection::None,
+                        preferred_col: Some(col),
+                    });
+                }
+            }
+            EditAction::AddNextOccurrence => {
+                let Some((start, end)) = self.selection_bounds() else {
+                    // Start by selecting the word under the cursor.
+                    self.select_word();
+                    return;
+                };
+                let needle = self.text_range(start, end);
+                let selected = self.all_selection_bounds();
+                // Search on from the last selection, skipping occurrences that are already
+                // selected, until the search comes back around to where it started.
+                let mut from = selected.last().map_or(end, |(_, end)| *end);
+                let mut first = None;
+                while let Some(found) = self.text.find_next(&needle, from) {
+                    if first == Some(found) {
+                        break;
                     }
-                } else {
-                    if self.cursor.line + 1 < self.lines.len() {
-                        self.cursor.line += 1;
-                        let line = self.lines.get(self.cursor.line).unwrap();
-                        if line.content.len() < self.cursor.index {
-                            self.cursor.index = line.content.len();
-                        }
+                    first.get_or_insert(found);
+                    if !selected.contains(&found) {
+                        self.other_cursors.push(CursorState {
+                            cursor: found.1,
+                            selection: Selection::Normal(found.0),
+                            preferred_col: None,
+                        });
+                        break;
                     }
+                    from = found.1;
                 }
             }
+            EditAction::CollapseCursors => {
+                self.other_cursors.clear();
+            }
+            EditAction::FindNext => {
+                self.select_next_match(self.cursor);
+            }
+            EditAction::FindPrevious => {
+                let before = self.selection_bounds().map_or(self.cursor, |(start, _)| start);
+                self.select_prev_match(before);
+            }
+            EditAction::NextHunk => {
+                self.goto_hunk(true);
+            }
+            EditAction::PreviousHunk => {
+                self.goto_hunk(false);
+            }
+            EditAction::RevertHunk => {
+                self.revert_hunk();
+            }
             EditAction::MovePrevWord => {
-                let line = self.lines.get(self.cursor.line).unwrap();
+                let line = self.text.line_str(self.cursor.line);
                 if self.cursor.index > 0 {
                     self.cursor.index = line
-                        .content
                         .unicode_word_indices()
                         .rev()
                         .map(|(i, _)| i)
@@ -980,67 +3864,210 @@ impl Buffer {
                         .unwrap_or(0);
                 } else if self.cursor.line > 0 {
                     self.cursor.line -= 1;
-                    self.cursor.index = self.lines.get(self.cursor.line).unwrap().content.len();
+                    self.cursor.index = self.text.line_len(self.cursor.line);
                 }
             }
             EditAction::MoveNextWord => {
-                let line = self.lines.get(self.cursor.line).unwrap();
-                if self.cursor.index < line.content.len() {
+                let line = self.text.line_str(self.cursor.line);
+                if self.cursor.index < line.len() {
                     self.cursor.index = line
-                        .content
                         .unicode_word_indices()
                         .map(|(i, word)| i + word.len())
                         .find(|&i| i > self.cursor.index)
-                        .unwrap_or(line.content.len());
-                } else if self.cursor.line + 1 < self.lines.len() {
+                        .unwrap_or(line.len());
+                } else if self.cursor.line + 1 < self.text.len_lines() {
                     self.cursor.line += 1;
                     self.cursor.index = 0;
                 }
             }
             EditAction::ScrollUp => {
-                self.scroll_y_offset = self.scroll_y_offset.saturating_sub(1);
+                self.scroll_line = self.scroll_line.saturating_sub(1);
             }
             EditAction::ScrollDown => {
-                self.scroll_y_offset = self.scroll_y_offset.saturating_add(1)
-                    .min(self.lines.len() as u16);
+                self.scroll_line = self.scroll_line.saturating_add(1)
+                    .min(self.text.len_lines() - 1);
             }
         }
     }
 }
 
-pub struct Line {
-    content: String,
+/// Splits the given line into the byte ranges of rows of at most `cols` graphemes each. Empty
+/// lines have a single empty row.
+fn wrap_rows(line: &str, cols: usize) -> Vec<Range<usize>> {
+    let mut rows = Vec::with_capacity(1);
+    let mut start = 0;
+    for (count, (index, _)) in line.grapheme_indices(true).enumerate() {
+        if count > 0 && count % cols == 0 {
+            rows.push(start..index);
+            start = index;
+        }
+    }
+    rows.push(start..line.len());
+
+    rows
 }
 
-impl Line {
-    pub fn append(&mut self, other: Self) {
-        self.content.push_str(&other.content);
+/// Removes a single trailing `\n` or `\r\n` from the given line, if it has one.
+fn strip_line_break(line: &str) -> &str {
+    match line.strip_suffix('\n') {
+        Some(line) => line.strip_suffix('\r').unwrap_or(line),
+        None => line,
     }
+}
 
-    pub fn split_off(&mut self, index: usize) -> Self {
-        let text = self.content.split_off(index);
+/// Describes why a search query couldn't be compiled, briefly enough to fit in the search prompt.
+fn search_error(error: regex::Error) -> String {
+    match error {
+        regex::Error::Syntax(_) => "invalid regex".to_string(),
+        error => error.to_string(),
+    }
+}
 
-        Self {
-            content: text,
+/// How a line differs from the last commit, as shown in the gutter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LineChange {
+    Added,
+    Modified,
+    /// The line is unchanged, but lines were removed just above it.
+    DeletedAbove,
+}
+
+/// Finds how the line at the given index differs from the last commit, given the buffer's hunks.
+fn line_change(hunks: &[LineHunk], line_index: usize) -> Option<LineChange> {
+    // The hunks are in order, so the first that doesn't end before the line is the only one
+    // that could affect it. A removal affects the line after it.
+    let index = hunks.partition_point(|hunk| {
+        let last_line = if hunk.new.is_empty() { hunk.new.start } else { hunk.new.end - 1 };
+        last_line < line_index
+    });
+    let hunk = hunks.get(index)?;
+    if hunk.new.contains(&line_index) {
+        Some(if hunk.old.is_empty() { LineChange::Added } else { LineChange::Modified })
+    } else if hunk.new.is_empty() && hunk.new.start == line_index {
+        Some(LineChange::DeletedAbove)
+    } else {
+        None
+    }
+}
+
+/// The directory that the editor's configuration is loaded from.
+fn config_dir() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("rust-editor"))
+}
+
+/// Hashes the content of a file, for telling whether it has changed.
+fn content_hash(bytes: &[u8]) -> u64 {
+    use std::hash::{Hash as _, Hasher as _};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Writes the given content to a temporary file next to `path`, then renames it over `path`, so
+/// that the file is never left partially written.
+fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write as _;
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = dir.join(tmp_name);
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
         }
+        std::fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
     }
+
+    result
 }
 
 pub struct Row<'a> {
-    pub num: usize,
+    /// The index of this row within its line.
     pub index: usize,
     pub line_index: usize,
-    pub content: &'a str,
+    /// The byte offset of this row's content into its line.
+    pub start: usize,
+    pub content: Cow<'a, str>,
+}
+
+impl Row<'_> {
+    /// Gets the column of the given byte index into this row's line, which must be in this row.
+    pub fn column(&self, index: usize) -> usize {
+        self.content[..index - self.start].graphemes(true).count()
+    }
+
+    /// Gets the byte index into this row's line of the given column, clamped to this row.
+    pub fn index_at_column(&self, col: usize) -> usize {
+        self.content.grapheme_indices(true)
+            .nth(col)
+            .map_or(self.start + self.content.len(), |(i, _)| self.start + i)
+    }
+
+    /// Gets the range of columns in this row that are between the two cursors, if any.
+    pub fn columns_between(&self, start: Cursor, end: Cursor) -> Option<Range<usize>> {
+        let row_start = Cursor { line: self.line_index, index: self.start };
+        let row_end = Cursor { line: self.line_index, index: self.start + self.content.len() };
+        if end < row_start || start > row_end {
+            return None;
+        }
+
+        let from = if start <= row_start { 0 } else { self.column(start.index) };
+        let to = if end >= row_end {
+            self.content.graphemes(true).count()
+        } else {
+            self.column(end.index)
+        };
+
+        Some(from..to)
+    }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum EditAction {
     Insert(char),
     ClearSelection,
     DeleteSelection,
+    /// Copies the selected text to the clipboard.
+    Copy,
+    /// Copies the selected text to the clipboard, then deletes it.
+    Cut,
+    /// Inserts the text on the clipboard in place of the selection.
+    Paste,
     NewLine,
     Backspace,
     Delete,
+    /// Moves the cursor to the given column and row of the visible text, clearing the selection
+    /// and any other cursors.
     Click((u16, u16)),
+    /// Moves the cursor to the given column and row of the visible text, keeping the selection.
+    Drag((u16, u16)),
+    /// Clicks at the given position and selects the word there.
+    DoubleClick((u16, u16)),
+    /// Clicks at the given position and selects the line there.
+    TripleClick((u16, u16)),
+    /// Selects the word under each cursor. The selection is then extended by whole words.
+    SelectWord,
+    /// Selects the line of each cursor, or extends a line selection by another line. The
+    /// selection is then extended by whole lines.
+    SelectLine,
+    SelectAll,
     MoveLeft,
     MoveRight,
     MoveUp,
@@ -1049,14 +4076,70 @@ pub enum EditAction {
     MoveNextWord,
     ScrollUp,
     ScrollDown,
+    /// Adds a cursor on the row above the topmost cursor.
+    AddCursorAbove,
+    /// Adds a cursor on the row below the bottommost cursor.
+    AddCursorBelow,
+    /// Selects the next occurrence of the selected text with a new cursor, or selects the word
+    /// under the cursor if nothing is selected.
+    AddNextOccurrence,
+    /// Removes every cursor but the primary one.
+    CollapseCursors,
+    /// Selects the next match of the buffer's search, scrolling it into view.
+    FindNext,
+    /// Selects the previous match of the buffer's search, scrolling it into view.
+    FindPrevious,
+    /// Moves to the next line that differs from the last commit, scrolling it into view.
+    NextHunk,
+    /// Moves to the previous line that differs from the last commit, scrolling it into view.
+    PreviousHunk,
+    /// Reverts the lines at the cursor that differ from the last commit.
+    RevertHunk,
+    Undo,
+    Redo,
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
-pub struct Cursor {
-    pub line: usize,
-    pub index: usize,
+impl EditAction {
+    /// Whether this action can change the text of the buffer it is performed on.
+    pub const fn edits_text(&self) -> bool {
+        matches!(
+            self,
+            EditAction::Insert(_)
+                | EditAction::DeleteSelection
+                | EditAction::Cut
+                | EditAction::Paste
+                | EditAction::NewLine
+                | EditAction::Backspace
+                | EditAction::Delete
+                | EditAction::RevertHunk
+                | EditAction::Undo
+                | EditAction::Redo
+        )
+    }
+}
+
+/// The state of a cursor other than a buffer's primary cursor.
+#[derive(Clone, Copy, Debug)]
+struct CursorState {
+    cursor: Cursor,
+    selection: Selection,
+    preferred_col: Option<usize>,
 }
 
+impl CursorState {
+    /// Moves the cursor and its selection with the given function.
+    fn map(&mut self, f: impl Fn(Cursor) -> Cursor) {
+        self.cursor = f(self.cursor);
+        self.selection = match self.selection {
+            Selection::None => Selection::None,
+            Selection::Normal(select) => Selection::Normal(f(select)),
+            Selection::Line(select) => Selection::Line(f(select)),
+            Selection::Word(select) => Selection::Word(f(select)),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Selection {
     None,
     Normal(Cursor),
@@ -1155,43 +4238,187 @@ mod util {
 
 
 
-pub struct ScopeSelectors {
-    pub comment: syntect::highlighting::ScopeSelector,
-    pub doc_comment: syntect::highlighting::ScopeSelectors,
-    pub function: syntect::highlighting::ScopeSelectors,
-    pub keyword: syntect::highlighting::ScopeSelectors,
-    pub types: syntect::highlighting::ScopeSelectors,
-}
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use unicode_segmentation::UnicodeSegmentation as _;
+
+    use super::*;
+
+    /// Pieces of text that are easy to split in the wrong place.
+    const TRICKY_PIECES: &[&str] = &[
+        // A letter with a combining accent, and a combining mark on its own.
+        "e\u{301}",
+        "\u{301}",
+        // A zero width joiner on its own, and joining a family emoji.
+        "\u{200d}",
+        "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}",
+        // An emoji with a skin tone modifier, and a flag.
+        "\u{1f44d}\u{1f3fd}",
+        "\u{1f1ef}\u{1f1f5}",
+        // A Devanagari conjunct, and Hangul jamo that combine into one syllable.
+        "\u{915}\u{94d}\u{937}",
+        "\u{1100}\u{1161}",
+        "word",
+        " ",
+        "\t",
+        "\r",
+        "\n",
+        "\r\n",
+    ];
+
+    /// Characters that join onto the grapheme before them.
+    const TRICKY_CHARS: &[char] = &['\u{301}', '\u{200d}', '\u{1f3fd}', '\u{1161}'];
 
-impl Default for ScopeSelectors {
-    fn default() -> ScopeSelectors {
-        ScopeSelectors {
-            comment: "comment - comment.block.attribute".parse().unwrap(),
-            doc_comment: "comment.line.documentation, comment.block.documentation".parse().unwrap(),
-            function: "entity.name.function, support.function".parse().unwrap(),
-            keyword: "keyword, storage".parse().unwrap(),
-            types: "entity.name.class, entity.name.struct, entity.name.enum, entity.name.type"
-                .parse().unwrap(),
+    /// Every action that doesn't depend on where the buffer is on screen, or on a search or the
+    /// last commit.
+    const ACTIONS: &[EditAction] = &[
+        EditAction::ClearSelection,
+        EditAction::DeleteSelection,
+        EditAction::Copy,
+        EditAction::Cut,
+        EditAction::Paste,
+        EditAction::NewLine,
+        EditAction::Backspace,
+        EditAction::Delete,
+        EditAction::SelectWord,
+        EditAction::SelectLine,
+        EditAction::SelectAll,
+        EditAction::MoveLeft,
+        EditAction::MoveRight,
+        EditAction::MoveUp,
+        EditAction::MoveDown,
+        EditAction::MovePrevWord,
+        EditAction::MoveNextWord,
+        EditAction::ScrollUp,
+        EditAction::ScrollDown,
+        EditAction::AddCursorAbove,
+        EditAction::AddCursorBelow,
+        EditAction::AddNextOccurrence,
+        EditAction::CollapseCursors,
+        EditAction::Undo,
+        EditAction::Redo,
+    ];
+
+    fn text() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            any::<String>(),
+            prop::sample::select(TRICKY_PIECES).prop_map(str::to_string),
+        ];
+        prop::collection::vec(piece, 0..32).prop_map(|pieces| pieces.concat())
+    }
+
+    fn action() -> impl Strategy<Value = EditAction> {
+        prop_oneof![
+            4 => prop::sample::select(ACTIONS),
+            1 => any::<char>().prop_map(EditAction::Insert),
+            1 => prop::sample::select(TRICKY_CHARS).prop_map(EditAction::Insert),
+            1 => (0u16..40, 0u16..10).prop_map(EditAction::Click),
+            1 => (0u16..40, 0u16..10).prop_map(EditAction::Drag),
+        ]
+    }
+
+    /// The byte indices of the grapheme boundaries in the given line, including its end.
+    fn grapheme_boundaries(line: &str) -> Vec<usize> {
+        line.grapheme_indices(true).map(|(index, _)| index).chain([line.len()]).collect()
+    }
+
+    fn check_grapheme_boundaries(buffer: &Buffer) -> std::result::Result<(), TestCaseError> {
+        for (cursor, selection) in buffer.cursors() {
+            let anchor = match selection {
+                Selection::None => None,
+                Selection::Normal(anchor) | Selection::Line(anchor) | Selection::Word(anchor) => {
+                    Some(anchor)
+                }
+            };
+            for cursor in std::iter::once(cursor).chain(anchor) {
+                prop_assert!(cursor.line < buffer.text.len_lines(), "{cursor:?} is past the end");
+                let line = buffer.text.line_str(cursor.line);
+                prop_assert!(
+                    grapheme_boundaries(&line).contains(&cursor.index),
+                    "{cursor:?} isn't on a grapheme boundary of {line:?}",
+                );
+            }
         }
+
+        Ok(())
     }
-}
 
-pub enum SourceScope {
-    Comment,
-    DocComment,
-    Function,
-    Keyword,
-    Type,
-}
+    proptest! {
+        #[test]
+        fn edits_keep_cursors_on_grapheme_boundaries(
+            text in text(),
+            cols in 0usize..12,
+            actions in prop::collection::vec(action(), 0..64),
+        ) {
+            let mut buffer = Buffer::new(BufferKind::Other, &text);
+            // Narrow buffers wrap lines, which vertical motions have to move across.
+            buffer.cols = cols;
+            buffer.rows = 10;
+            let mut clipboard = Register::default();
+            let original = buffer.text();
+            for action in actions {
+                buffer.perform_action(action, &mut clipboard);
+                check_grapheme_boundaries(&buffer)?;
+            }
 
-impl SourceScope {
-    pub const fn color(&self) -> Color {
-        match self {
-            SourceScope::Comment => Color::new(0x59, 0x59, 0x6d, 0xff),
-            SourceScope::DocComment => Color::new(0x87, 0xb6, 0x97, 0xff),
-            SourceScope::Function => Color::new(0x95, 0xb7, 0xdf, 0xff),
-            SourceScope::Keyword => Color::new(0xd9, 0x6d, 0x81, 0xff),
-            SourceScope::Type => Color::new(0x8b, 0x8b, 0x95, 0xff),
+            // Undoing every edit puts back the original text, down to any stray `\r`.
+            while buffer.undo() {}
+            prop_assert_eq!(buffer.text(), original);
+        }
+
+        #[test]
+        fn vertical_motions_keep_the_column(
+            text in text(),
+            line in any::<prop::sample::Index>(),
+            col in 0usize..64,
+        ) {
+            let mut buffer = Buffer::new(BufferKind::Other, &text);
+            let mut clipboard = Register::default();
+            let line = line.index(buffer.text.len_lines());
+            let boundaries = grapheme_boundaries(&buffer.text.line_str(line));
+            let start = Cursor { line, index: boundaries[col.min(boundaries.len() - 1)] };
+            buffer.cursor = start;
+            let (_, start_col) = buffer.cursor_row_col(start);
+
+            // Each line below is as close to the starting column as its length allows.
+            let mut moves = 0;
+            while buffer.cursor.line + 1 < buffer.text.len_lines() {
+                buffer.perform_action(EditAction::MoveDown, &mut clipboard);
+                let boundaries = grapheme_boundaries(&buffer.text.line_str(buffer.cursor.line));
+                let expected = boundaries[start_col.min(boundaries.len() - 1)];
+                prop_assert_eq!(buffer.cursor, Cursor { line: line + moves + 1, index: expected });
+                moves += 1;
+            }
+            // Moving back up returns to the starting column, however short the lines between.
+            for _ in 0..moves {
+                buffer.perform_action(EditAction::MoveUp, &mut clipboard);
+            }
+            prop_assert_eq!(buffer.cursor, start);
         }
     }
+
+    #[test]
+    fn multi_line_selections_are_cut_and_pasted_as_single_edits() {
+        let mut buffer = Buffer::new(BufferKind::Other, "one\ntwo\nthree\n");
+        let mut clipboard = Register::default();
+        let start = Cursor { line: 0, index: 1 };
+        buffer.select_range(start, Cursor { line: 2, index: 2 });
+        buffer.perform_action(EditAction::Copy, &mut clipboard);
+        assert_eq!(clipboard.read().as_deref(), Some("ne\ntwo\nth"));
+        assert_eq!(buffer.text(), "one\ntwo\nthree\n");
+
+        buffer.perform_action(EditAction::Cut, &mut clipboard);
+        assert_eq!(buffer.text(), "oree\n");
+        assert_eq!(buffer.cursor, start);
+
+        // Pasting puts every line back, and is undone in one step.
+        buffer.perform_action(EditAction::Paste, &mut clipboard);
+        assert_eq!(buffer.text(), "one\ntwo\nthree\n");
+        assert_eq!(buffer.cursor, Cursor { line: 2, index: 2 });
+        assert!(buffer.undo());
+        assert_eq!(buffer.text(), "oree\n");
+        assert!(buffer.undo());
+        assert_eq!(buffer.text(), "one\ntwo\nthree\n");
+    }
 }
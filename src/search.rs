@@ -0,0 +1,237 @@
+//! Text search
+
+
+
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{Cursor, TextBuffer};
+
+
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SearchOptions {
+    pub case_insensitive: bool,
+    /// Only match whole words.
+    pub whole_word: bool,
+    /// Treat the query as a regular expression, rather than literal text.
+    pub regex: bool,
+}
+
+/// A compiled search query. Matches never span more than one line.
+#[derive(Clone, Debug)]
+pub struct Search {
+    query: String,
+    options: SearchOptions,
+    regex: Regex,
+}
+
+impl Search {
+    pub fn new(query: &str, options: SearchOptions) -> Result<Self, regex::Error> {
+        let mut pattern = if options.regex {
+            format!("(?:{query})")
+        } else {
+            regex::escape(query)
+        };
+        if options.whole_word {
+            pattern = format!(r"\b{pattern}\b");
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(options.case_insensitive)
+            .build()?;
+
+        Ok(Self {
+            query: query.to_string(),
+            options,
+            regex,
+        })
+    }
+
+    #[inline]
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    #[inline]
+    pub fn options(&self) -> SearchOptions {
+        self.options
+    }
+
+    #[inline]
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    /// Finds every match in the given line, as byte ranges into it. Empty matches are skipped.
+    pub fn find_in_line<'a>(&'a self, line: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+        self.regex.find_iter(line)
+            .filter(|found| !found.is_empty())
+            .map(|found| found.range())
+    }
+
+    /// Finds the first match that starts at or after `from`, wrapping around to the start of the
+    /// text if there are none.
+    pub fn find_next(&self, text: &TextBuffer, from: Cursor) -> Option<(Cursor, Cursor)> {
+        let line_count = text.len_lines();
+        let from_line = from.line.min(line_count - 1);
+        // The starting line is searched twice, once from the cursor and once before it.
+        for n in 0..=line_count {
+            let line_index = (from_line + n) % line_count;
+            let line = text.line_str(line_index);
+            let found = self.find_in_line(&line).find(|range| match n {
+                0 => range.start >= from.index,
+                n if n == line_count => range.start < from.index,
+                _ => true,
+            });
+            if let Some(range) = found {
+                return Some(match_cursors(line_index, range));
+            }
+        }
+
+        None
+    }
+
+    /// Finds the last match that starts before `before`, wrapping around to the end of the text
+    /// if there are none.
+    pub fn find_prev(&self, text: &TextBuffer, before: Cursor) -> Option<(Cursor, Cursor)> {
+        let line_count = text.len_lines();
+        let before_line = before.line.min(line_count - 1);
+        for n in 0..=line_count {
+            let line_index = (before_line + line_count - n % line_count) % line_count;
+            let line = text.line_str(line_index);
+            let found = self.find_in_line(&line)
+                .filter(|range| match n {
+                    0 => range.start < before.index,
+                    n if n == line_count => range.start >= before.index,
+                    _ => true,
+                })
+                .last();
+            if let Some(range) = found {
+                return Some(match_cursors(line_index, range));
+            }
+        }
+
+        None
+    }
+}
+
+/// What the matches of a [`Search`] are replaced with.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Replace {
+    /// The replacement text. For regex searches, `$1` or `${name}` is replaced with the text of
+    /// that capture group, and `$$` with a single `$`.
+    pub template: String,
+    /// Change the case of the replacement to match the text it replaces, when that text is all
+    /// uppercase, all lowercase, or capitalized.
+    pub preserve_case: bool,
+}
+
+impl Search {
+    /// Gets the text that the match at the given range of the line should be replaced with.
+    pub fn replacement(&self, line: &str, range: Range<usize>, replace: &Replace) -> String {
+        let mut text = String::new();
+        let captures = self.options.regex
+            .then(|| self.regex.captures_at(line, range.start))
+            .flatten();
+        match captures {
+            Some(captures) => captures.expand(&replace.template, &mut text),
+            None => text.push_str(&replace.template),
+        }
+
+        if replace.preserve_case {
+            match_case(&line[range], &text)
+        } else {
+            text
+        }
+    }
+}
+
+/// Changes the case of `text` to match `like`.
+fn match_case(like: &str, text: &str) -> String {
+    let mut letters = like.chars().filter(|ch| ch.is_alphabetic());
+    let Some(first) = letters.next() else { return text.to_string(); };
+    let rest: Vec<char> = letters.collect();
+
+    if first.is_uppercase() && !rest.is_empty() && rest.iter().all(|ch| ch.is_uppercase()) {
+        text.to_uppercase()
+    } else if first.is_lowercase() && rest.iter().all(|ch| ch.is_lowercase()) {
+        text.to_lowercase()
+    } else if first.is_uppercase() && rest.iter().all(|ch| ch.is_lowercase()) {
+        let mut chars = text.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    } else {
+        text.to_string()
+    }
+}
+
+/// A line that replacing the matches of a search would change.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplacePreview {
+    pub line_index: usize,
+    pub before: String,
+    pub after: String,
+}
+
+fn match_cursors(line_index: usize, range: Range<usize>) -> (Cursor, Cursor) {
+    (
+        Cursor { line: line_index, index: range.start },
+        Cursor { line: line_index, index: range.end },
+    )
+}
+
+
+
+/// A search in a buffer, along with a count of its matches.
+///
+/// Counting every match in a large buffer takes a while, so matches are counted a chunk of lines
+/// at a time with [`SearchState::count_matches`].
+pub struct SearchState {
+    pub search: Search,
+    /// The version of the buffer that the matches are being counted in.
+    version: u64,
+    /// The number of lines that have been counted so far.
+    counted_lines: usize,
+    count: usize,
+    done: bool,
+}
+
+impl SearchState {
+    pub fn new(search: Search, version: u64) -> Self {
+        Self {
+            search,
+            version,
+            counted_lines: 0,
+            count: 0,
+            done: false,
+        }
+    }
+
+    /// Counts the matches in up to `max_lines` more lines of the text, starting over if the text
+    /// has changed since counting began. Returns whether there are lines left to count.
+    pub fn count_matches(&mut self, text: &TextBuffer, version: u64, max_lines: usize) -> bool {
+        if version != self.version {
+            self.version = version;
+            self.counted_lines = 0;
+            self.count = 0;
+        }
+
+        let end = (self.counted_lines + max_lines).min(text.len_lines());
+        for line_index in self.counted_lines..end {
+            self.count += self.search.find_in_line(&text.line_str(line_index)).count();
+        }
+        self.counted_lines = end;
+        self.done = end == text.len_lines();
+
+        !self.done
+    }
+
+    /// The number of matches counted so far, and whether that is all of them.
+    #[inline]
+    pub fn count(&self) -> (usize, bool) {
+        (self.count, self.done)
+    }
+}
@@ -0,0 +1,369 @@
+//! Text storage
+
+
+
+use std::{borrow::Cow, ops::Range};
+
+use ropey::{Rope, RopeSlice};
+use unicode_segmentation::GraphemeCursor;
+
+
+
+/// A position in a text buffer.
+///
+/// Cursors are ordered by line, then by index.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Cursor {
+    /// The index of the line.
+    pub line: usize,
+    /// The byte offset into the line. This is always on a grapheme boundary.
+    pub index: usize,
+}
+
+impl Cursor {
+    /// Gets where this cursor ends up after `start..end` is filled with inserted text.
+    pub fn after_insert(self, start: Cursor, end: Cursor) -> Cursor {
+        if self < start {
+            self
+        } else if self.line == start.line {
+            Cursor { line: end.line, index: end.index + self.index - start.index }
+        } else {
+            Cursor { line: self.line + end.line - start.line, index: self.index }
+        }
+    }
+
+    /// Gets where this cursor ends up after the text in `start..end` is deleted.
+    pub fn after_delete(self, start: Cursor, end: Cursor) -> Cursor {
+        if self <= start {
+            self
+        } else if self <= end {
+            start
+        } else if self.line == end.line {
+            Cursor { line: start.line, index: start.index + self.index - end.index }
+        } else {
+            Cursor { line: self.line - (end.line - start.line), index: self.index }
+        }
+    }
+}
+
+/// Line-indexed text, backed by a rope so that edits and line lookups are `O(log n)` in the size
+/// of the text.
+///
+/// Lines are always separated by a single `\n`, regardless of the line endings of the content the
+/// text was created from or that is inserted into it.
+///
+/// Cloning is cheap, since the clone shares the original's storage until either is edited.
+#[derive(Clone)]
+pub struct TextBuffer {
+    rope: Rope,
+}
+
+impl TextBuffer {
+    pub fn new(content: &str) -> Self {
+        Self {
+            rope: Rope::from_str(&normalize_line_breaks(content)),
+        }
+    }
+
+    /// The number of lines in the text. This is always at least 1.
+    #[inline]
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    /// The total length of the text, in bytes.
+    #[inline]
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    /// Gets the line at the given index, without its line break.
+    pub fn line(&self, index: usize) -> RopeSlice<'_> {
+        let line = self.rope.line(index);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            line.slice(..len - 1)
+        } else {
+            line
+        }
+    }
+
+    /// Gets the line at the given index as a string, without its line break.
+    ///
+    /// This only allocates if the line is split across multiple chunks of the rope.
+    #[inline]
+    pub fn line_str(&self, index: usize) -> Cow<'_, str> {
+        self.line(index).into()
+    }
+
+    /// The length of the line at the given index in bytes, not including its line break.
+    #[inline]
+    pub fn line_len(&self, index: usize) -> usize {
+        self.line(index).len_bytes()
+    }
+
+    /// Iterates over the lines of the text, without their line breaks.
+    pub fn lines(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        (0..self.len_lines()).map(|index| self.line_str(index))
+    }
+
+    /// Inserts the given content at the cursor, returning the position at the end of the inserted
+    /// content.
+    pub fn insert(&mut self, at: Cursor, content: &str) -> Cursor {
+        self.insert_verbatim(at, &normalize_line_breaks(content))
+    }
+
+    /// Inserts the given content at the cursor without normalizing its line breaks, returning the
+    /// position at the end of the inserted content.
+    ///
+    /// This is for putting back text that was removed from this text, which can have a `\r` at
+    /// the end of a line that would otherwise be lost.
+    pub fn insert_verbatim(&mut self, at: Cursor, content: &str) -> Cursor {
+        let char_index = self.cursor_to_char(at);
+        self.rope.insert(char_index, content);
+
+        self.char_to_cursor(char_index + content.chars().count())
+    }
+
+    /// Removes the text between the two cursors.
+    pub fn remove(&mut self, start: Cursor, end: Cursor) {
+        let start = self.cursor_to_char(start);
+        let end = self.cursor_to_char(end);
+        self.rope.remove(start..end);
+    }
+
+    /// Gets the text between the two cursors, with lines separated by `\n`.
+    pub fn slice(&self, start: Cursor, end: Cursor) -> String {
+        let start = self.cursor_to_char(start);
+        let end = self.cursor_to_char(end);
+        self.rope.slice(start..end).to_string()
+    }
+
+    /// Iterates over the chunks of the text, in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        self.rope.chunks()
+    }
+
+    /// Converts a cursor to a char index into the whole text. Cursors past the end of their line
+    /// are clamped to it.
+    pub fn cursor_to_char(&self, cursor: Cursor) -> usize {
+        let line = cursor.line.min(self.len_lines() - 1);
+        let index = cursor.index.min(self.line_len(line));
+        self.rope.byte_to_char(self.rope.line_to_byte(line) + index)
+    }
+
+    /// Finds the first occurrence of `needle` that starts at or after `from`, wrapping around to
+    /// the start of the text if there are none. Returns the start and end of the occurrence.
+    pub fn find_next(&self, needle: &str, from: Cursor) -> Option<(Cursor, Cursor)> {
+        if needle.is_empty() {
+            return None;
+        }
+        let line_count = self.len_lines();
+        let from_line = from.line.min(line_count - 1);
+        // The starting line is searched twice, once from the cursor and once before it.
+        for n in 0..=line_count {
+            let line_index = (from_line + n) % line_count;
+            let from_index = if n == 0 { from.index } else { 0 };
+            let found = self
+                .find_on_line(needle, line_index, from_index)
+                .filter(|(start, _)| n < line_count || start.index < from.index);
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        None
+    }
+
+    /// Finds the first occurrence of `needle` that starts on the line at the given index, at or
+    /// after `from_index`. Occurrences of needles with line breaks can end on a later line.
+    fn find_on_line(
+        &self,
+        needle: &str,
+        line_index: usize,
+        from_index: usize,
+    ) -> Option<(Cursor, Cursor)> {
+        let line = self.line_str(line_index);
+        let Some((first, rest)) = needle.split_once('\n') else {
+            let start = from_index + line.get(from_index..)?.find(needle)?;
+            let end = start + needle.len();
+            return Some((
+                Cursor { line: line_index, index: start },
+                Cursor { line: line_index, index: end },
+            ));
+        };
+
+        // The first line of the needle has to run to the end of the line, and the last has to
+        // start the line it ends on, with any lines in between matching whole lines.
+        let start = line.len().checked_sub(first.len())?;
+        if start < from_index || !line.ends_with(first) {
+            return None;
+        }
+        let mut parts = rest.split('\n').peekable();
+        let mut end_line = line_index;
+        while let Some(part) = parts.next() {
+            end_line += 1;
+            if end_line >= self.len_lines() {
+                return None;
+            }
+            let line = self.line_str(end_line);
+            if parts.peek().is_some() && *line != *part {
+                return None;
+            } else if parts.peek().is_none() && line.starts_with(part) {
+                return Some((
+                    Cursor { line: line_index, index: start },
+                    Cursor { line: end_line, index: part.len() },
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Converts a char index into the whole text to a cursor.
+    pub fn char_to_cursor(&self, char_index: usize) -> Cursor {
+        let line = self.rope.char_to_line(char_index);
+        let index = self.rope.char_to_byte(char_index) - self.rope.line_to_byte(line);
+
+        Cursor { line, index }
+    }
+}
+
+/// A run of lines that differ between two versions of a text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LineHunk {
+    /// The lines of the old text that were replaced. Empty if lines were only added.
+    pub old: Range<usize>,
+    /// The lines of the new text that replaced them. Empty if lines were only removed.
+    pub new: Range<usize>,
+}
+
+/// Finds the runs of lines that differ between the old and new lines, in order.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<LineHunk> {
+    // The ranges are worked out from the lengths of the diff's operations rather than their
+    // indices, which aren't always in order.
+    let mut hunks: Vec<LineHunk> = vec![];
+    let (mut old_start, mut new_start) = (0, 0);
+    for op in similar::capture_diff_slices(similar::Algorithm::Myers, old, new) {
+        let old_range = old_start..old_start + op.old_range().len();
+        let new_range = new_start..new_start + op.new_range().len();
+        (old_start, new_start) = (old_range.end, new_range.end);
+        if op.tag() == similar::DiffTag::Equal {
+            continue;
+        }
+        // Deletions and insertions next to each other are one replacement.
+        match hunks.last_mut() {
+            Some(last) if last.old.end == old_range.start && last.new.end == new_range.start => {
+                last.old.end = old_range.end;
+                last.new.end = new_range.end;
+            }
+            _ => hunks.push(LineHunk { old: old_range, new: new_range }),
+        }
+    }
+
+    hunks
+}
+
+/// A line of a unified diff.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffLine {
+    /// The line, starting with ` `, `-` or `+` for unchanged, removed and added lines, or a hunk
+    /// header starting with `@@`.
+    pub text: String,
+    /// The index of the line in the new text that this line is at, or is nearest to if it was
+    /// removed.
+    pub new_line: usize,
+}
+
+/// Lays out the given hunks between the old and new lines as a unified diff, with `context`
+/// unchanged lines around each. Hunks close enough to share their context are shown as one.
+pub fn unified_diff(
+    old: &[&str],
+    new: &[&str],
+    hunks: &[LineHunk],
+    context: usize,
+) -> Vec<DiffLine> {
+    // The start of a range in a hunk header is the line before it if it is empty.
+    fn header_range(range: Range<usize>) -> String {
+        let start = if range.is_empty() { range.start } else { range.start + 1 };
+        format!("{start},{}", range.len())
+    }
+
+    let mut lines = vec![];
+    let mut rest = hunks;
+    while let Some(first) = rest.first() {
+        let group_len = 1 + rest.windows(2)
+            .take_while(|pair| pair[1].old.start - pair[0].old.end <= 2 * context)
+            .count();
+        let (group, next) = rest.split_at(group_len);
+        rest = next;
+        let last = &group[group_len - 1];
+
+        // The lines around the hunks are unchanged, so there are as many of them in both texts.
+        let before = context.min(first.new.start);
+        let after = context.min(new.len() - last.new.end);
+        let old_range = first.old.start - before..last.old.end + after;
+        let new_range = first.new.start - before..last.new.end + after;
+        let header = format!(
+            "@@ -{} +{} @@",
+            header_range(old_range),
+            header_range(new_range.clone()),
+        );
+        lines.push(DiffLine { text: header, new_line: first.new.start });
+
+        let mut new_line = new_range.start;
+        for hunk in group {
+            for line in &new[new_line..hunk.new.start] {
+                lines.push(DiffLine { text: format!(" {line}"), new_line });
+                new_line += 1;
+            }
+            for line in &old[hunk.old.clone()] {
+                lines.push(DiffLine { text: format!("-{line}"), new_line: hunk.new.start });
+            }
+            for line in &new[hunk.new.clone()] {
+                lines.push(DiffLine { text: format!("+{line}"), new_line });
+                new_line += 1;
+            }
+        }
+        for line in &new[new_line..new_range.end] {
+            lines.push(DiffLine { text: format!(" {line}"), new_line });
+            new_line += 1;
+        }
+    }
+
+    lines
+}
+
+/// Replaces every `\r\n` in the content with `\n`, only allocating if there are any.
+fn normalize_line_breaks(content: &str) -> Cow<'_, str> {
+    if content.contains("\r\n") {
+        Cow::Owned(content.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(content)
+    }
+}
+
+/// Whether `index` is on a grapheme boundary in the given line.
+pub fn is_grapheme_boundary(line: &str, index: usize) -> bool {
+    GraphemeCursor::new(index, line.len(), true)
+        .is_boundary(line, 0)
+        .unwrap_or(true)
+}
+
+/// Gets the byte index of the grapheme boundary before `index` in the given line.
+pub fn prev_grapheme_boundary(line: &str, index: usize) -> usize {
+    GraphemeCursor::new(index, line.len(), true)
+        .prev_boundary(line, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Gets the byte index of the grapheme boundary after `index` in the given line.
+pub fn next_grapheme_boundary(line: &str, index: usize) -> usize {
+    GraphemeCursor::new(index, line.len(), true)
+        .next_boundary(line, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(line.len())
+}
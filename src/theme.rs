@@ -0,0 +1,350 @@
+//! Color themes
+
+
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use bog::prelude::Color;
+use serde::Deserialize;
+
+use crate::{SourceScope, GRAY_1, GRAY_3, GRAY_5, GRAY_6, GRAY_7, GRAY_9};
+
+
+
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub name: String,
+    /// The color behind everything.
+    pub background: Color,
+    /// The workspace name at the top of the side panel.
+    pub header_text: Color,
+    /// The file and directory names in the side panel.
+    pub entry_text: Color,
+    /// The names of ignored and hidden files and directories, when they are shown.
+    pub ignored_entry_text: Color,
+    /// The line numbers in the gutter.
+    pub gutter_text: Color,
+    /// The line number of the line the cursor is on.
+    pub gutter_current_text: Color,
+    /// Lines and files that have been added since the last commit, and untracked files.
+    pub vc_added: Color,
+    /// Lines and files that have been changed since the last commit.
+    pub vc_modified: Color,
+    /// Where lines have been removed since the last commit, and files with conflicts.
+    pub vc_deleted: Color,
+    /// Buffer text that isn't highlighted as any scope.
+    pub text: Color,
+    pub selection: Color,
+    /// The background of text that matches the current search.
+    pub search_match: Color,
+    pub cursor: Color,
+    /// The color of each highlighted scope. Scopes that are missing are drawn as plain text.
+    pub scopes: HashMap<SourceScope, Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            name: "Default".into(),
+            background: GRAY_1,
+            header_text: GRAY_7,
+            entry_text: GRAY_5,
+            ignored_entry_text: GRAY_3,
+            gutter_text: GRAY_5,
+            gutter_current_text: GRAY_6,
+            vc_added: Color::new(0x87, 0xb6, 0x97, 0xff),
+            vc_modified: Color::new(0x95, 0xb7, 0xdf, 0xff),
+            vc_deleted: Color::new(0xd9, 0x6d, 0x81, 0xff),
+            text: GRAY_7,
+            selection: GRAY_3,
+            search_match: Color::new(0x4d, 0x48, 0x35, 0xff),
+            cursor: GRAY_9,
+            scopes: HashMap::from([
+                (SourceScope::Comment, Color::new(0x59, 0x59, 0x6d, 0xff)),
+                (SourceScope::DocComment, Color::new(0x87, 0xb6, 0x97, 0xff)),
+                (SourceScope::Function, Color::new(0x95, 0xb7, 0xdf, 0xff)),
+                (SourceScope::Keyword, Color::new(0xd9, 0x6d, 0x81, 0xff)),
+                (SourceScope::Type, Color::new(0x8b, 0x8b, 0x95, 0xff)),
+                (SourceScope::String, Color::new(0xc4, 0xb1, 0x7b, 0xff)),
+                (SourceScope::Number, Color::new(0xd9, 0xa0, 0x6d, 0xff)),
+                (SourceScope::Macro, Color::new(0x7f, 0xc4, 0xc4, 0xff)),
+                (SourceScope::Lifetime, Color::new(0xc9, 0x8b, 0xd9, 0xff)),
+                (SourceScope::Attribute, Color::new(0x9d, 0x8f, 0xc4, 0xff)),
+                (SourceScope::Constant, Color::new(0xd9, 0xa0, 0x6d, 0xff)),
+                (SourceScope::Operator, Color::new(0xa3, 0xa3, 0xad, 0xff)),
+            ]),
+        }
+    }
+}
+
+impl Theme {
+    /// Gets the color that text highlighted as the given scope should be drawn in.
+    pub fn scope_color(&self, scope: Option<SourceScope>) -> Color {
+        scope.and_then(|scope| self.scopes.get(&scope))
+            .copied()
+            .unwrap_or(self.text)
+    }
+
+    /// Loads a theme from a TOML file. Any colors that the file doesn't set are taken from the
+    /// default theme.
+    ///
+    /// ```toml
+    /// name = "Example"
+    ///
+    /// [ui]
+    /// background = "#1d1d27"
+    /// cursor = "#bfbfc5"
+    ///
+    /// [scopes]
+    /// keyword = "#d96d81"
+    /// ```
+    pub fn from_toml(path: &Path) -> Result<Self, ThemeError> {
+        let content = std::fs::read_to_string(path).map_err(|error| ThemeError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let file: ThemeFile = toml::from_str(&content).map_err(|error| ThemeError::Toml {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let color = |key: &str, value: &Option<String>, default: Color| match value {
+            Some(value) => parse_color(value).ok_or_else(|| ThemeError::InvalidColor {
+                path: path.to_path_buf(),
+                key: key.to_string(),
+                value: value.clone(),
+            }),
+            None => Ok(default),
+        };
+
+        let default = Theme::default();
+        let mut scopes = default.scopes.clone();
+        for (name, value) in &file.scopes {
+            let scope = SourceScope::from_name(name).ok_or_else(|| ThemeError::UnknownScope {
+                path: path.to_path_buf(),
+                name: name.clone(),
+            })?;
+            scopes.insert(scope, color(name, &Some(value.clone()), default.text)?);
+        }
+
+        Ok(Self {
+            name: file.name.unwrap_or_else(|| theme_name(path)),
+            background: color("background", &file.ui.background, default.background)?,
+            header_text: color("header_text", &file.ui.header_text, default.header_text)?,
+            entry_text: color("entry_text", &file.ui.entry_text, default.entry_text)?,
+            ignored_entry_text: color(
+                "ignored_entry_text",
+                &file.ui.ignored_entry_text,
+                default.ignored_entry_text,
+            )?,
+            gutter_text: color("gutter_text", &file.ui.gutter_text, default.gutter_text)?,
+            gutter_current_text: color(
+                "gutter_current_text",
+                &file.ui.gutter_current_text,
+                default.gutter_current_text,
+            )?,
+            vc_added: color("vc_added", &file.ui.vc_added, default.vc_added)?,
+            vc_modified: color("vc_modified", &file.ui.vc_modified, default.vc_modified)?,
+            vc_deleted: color("vc_deleted", &file.ui.vc_deleted, default.vc_deleted)?,
+            text: color("text", &file.ui.text, default.text)?,
+            selection: color("selection", &file.ui.selection, default.selection)?,
+            search_match: color("search_match", &file.ui.search_match, default.search_match)?,
+            cursor: color("cursor", &file.ui.cursor, default.cursor)?,
+            scopes,
+        })
+    }
+
+    /// Imports a TextMate `.tmTheme` file. Scope colors are taken from the style the theme gives
+    /// to a representative scope for each [`SourceScope`].
+    pub fn from_tm_theme(path: &Path) -> Result<Self, ThemeError> {
+        use syntect::highlighting::{Highlighter, ThemeSet};
+        use syntect::parsing::{Scope, ScopeStack};
+
+        let tm_theme = ThemeSet::get_theme(path).map_err(|error| ThemeError::TmTheme {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let default = Theme::default();
+        let settings = &tm_theme.settings;
+        let color = |color: Option<syntect::highlighting::Color>, default: Color| {
+            color.map_or(default, |c| Color::new(c.r, c.g, c.b, c.a))
+        };
+        let text = color(settings.foreground, default.text);
+
+        let highlighter = Highlighter::new(&tm_theme);
+        let scopes = SourceScope::ALL.iter()
+            .filter_map(|scope| {
+                let mut stack = ScopeStack::new();
+                stack.push(Scope::new(scope.representative_scope()).ok()?);
+                let style = highlighter.style_for_stack(stack.as_slice());
+                Some((*scope, color(Some(style.foreground), text)))
+            })
+            .collect();
+
+        Ok(Self {
+            name: tm_theme.name.clone().unwrap_or_else(|| theme_name(path)),
+            background: color(settings.background, default.background),
+            header_text: text,
+            entry_text: color(settings.gutter_foreground, default.entry_text),
+            ignored_entry_text: color(settings.guide, default.ignored_entry_text),
+            gutter_text: color(settings.gutter_foreground, default.gutter_text),
+            gutter_current_text: text,
+            // TextMate themes have no colors for version control.
+            vc_added: default.vc_added,
+            vc_modified: default.vc_modified,
+            vc_deleted: default.vc_deleted,
+            text,
+            selection: color(settings.selection, default.selection),
+            search_match: color(settings.find_highlight, default.search_match),
+            cursor: color(settings.caret, default.cursor),
+            scopes,
+        })
+    }
+}
+
+/// The directory that user themes are loaded from.
+pub fn themes_dir() -> Option<PathBuf> {
+    crate::config_dir().map(|dir| dir.join("themes"))
+}
+
+/// Loads every `.toml` and `.tmTheme` theme in the given directory, sorted by name. Themes that
+/// fail to load are returned as errors, and don't prevent the others from loading.
+pub fn load_themes(dir: &Path) -> (Vec<Theme>, Vec<ThemeError>) {
+    let mut themes = vec![];
+    let mut errors = vec![];
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Having no themes directory is the same as having no themes.
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return (themes, errors),
+        Err(error) => {
+            errors.push(ThemeError::Io { path: dir.to_path_buf(), error });
+            return (themes, errors);
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let result = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Theme::from_toml(&path),
+            Some("tmTheme") => Theme::from_tm_theme(&path),
+            _ => continue,
+        };
+        match result {
+            Ok(theme) => themes.push(theme),
+            Err(error) => errors.push(error),
+        }
+    }
+    themes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    (themes, errors)
+}
+
+fn theme_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Parses a `#rrggbb` or `#rrggbbaa` color.
+fn parse_color(value: &str) -> Option<Color> {
+    let hex = value.strip_prefix('#')?;
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok();
+
+    match hex.len() {
+        6 => Some(Color::new(channel(0)?, channel(2)?, channel(4)?, 0xff)),
+        8 => Some(Color::new(channel(0)?, channel(2)?, channel(4)?, channel(6)?)),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeFile {
+    name: Option<String>,
+    #[serde(default)]
+    ui: UiColors,
+    #[serde(default)]
+    scopes: HashMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UiColors {
+    background: Option<String>,
+    header_text: Option<String>,
+    entry_text: Option<String>,
+    ignored_entry_text: Option<String>,
+    gutter_text: Option<String>,
+    gutter_current_text: Option<String>,
+    vc_added: Option<String>,
+    vc_modified: Option<String>,
+    vc_deleted: Option<String>,
+    text: Option<String>,
+    selection: Option<String>,
+    search_match: Option<String>,
+    cursor: Option<String>,
+}
+
+
+
+#[derive(Debug)]
+pub enum ThemeError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Toml {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    TmTheme {
+        path: PathBuf,
+        error: syntect::LoadingError,
+    },
+    InvalidColor {
+        path: PathBuf,
+        key: String,
+        value: String,
+    },
+    UnknownScope {
+        path: PathBuf,
+        name: String,
+    },
+}
+
+impl std::fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThemeError::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            ThemeError::Toml { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            ThemeError::TmTheme { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            ThemeError::InvalidColor { path, key, value } => {
+                write!(f, "{}: invalid color for `{}`: {:?}", path.display(), key, value)
+            }
+            ThemeError::UnknownScope { path, name } => {
+                write!(f, "{}: unknown scope `{}`", path.display(), name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThemeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ThemeError::Io { error, .. } => Some(error),
+            ThemeError::Toml { error, .. } => Some(error),
+            ThemeError::TmTheme { error, .. } => Some(error),
+            ThemeError::InvalidColor { .. } | ThemeError::UnknownScope { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Version control detection
+
+
+
+use std::path::{Path, PathBuf};
+
+
+
+/// A kind of version control that a workspace can be under.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum VcsKind {
+    Git,
+    Mercurial,
+    Subversion,
+}
+
+impl VcsKind {
+    /// The name of the version control system's command.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VcsKind::Git => "git",
+            VcsKind::Mercurial => "hg",
+            VcsKind::Subversion => "svn",
+        }
+    }
+}
+
+impl std::fmt::Display for VcsKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Something that can tell whether a directory is the root of a working copy of one kind of
+/// version control.
+pub trait VcsBackend: Sync {
+    fn kind(&self) -> VcsKind;
+    /// Whether the given directory is the root of a working copy.
+    fn is_root(&self, dir: &Path) -> bool;
+}
+
+/// Finds git working trees by their `.git` directory, or the `.git` file that points to the
+/// repository of a linked worktree or submodule.
+pub struct GitBackend;
+
+impl VcsBackend for GitBackend {
+    fn kind(&self) -> VcsKind {
+        VcsKind::Git
+    }
+
+    fn is_root(&self, dir: &Path) -> bool {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return true;
+        }
+        // Other files named `.git` aren't working trees.
+        std::fs::read_to_string(&dot_git)
+            .is_ok_and(|content| content.starts_with("gitdir:"))
+    }
+}
+
+/// Finds Mercurial working directories by their `.hg` directory.
+pub struct MercurialBackend;
+
+impl VcsBackend for MercurialBackend {
+    fn kind(&self) -> VcsKind {
+        VcsKind::Mercurial
+    }
+
+    fn is_root(&self, dir: &Path) -> bool {
+        dir.join(".hg").is_dir()
+    }
+}
+
+/// Finds Subversion working copies by their `.svn` directory, which is only at their root since
+/// Subversion 1.7.
+pub struct SubversionBackend;
+
+impl VcsBackend for SubversionBackend {
+    fn kind(&self) -> VcsKind {
+        VcsKind::Subversion
+    }
+
+    fn is_root(&self, dir: &Path) -> bool {
+        dir.join(".svn").is_dir()
+    }
+}
+
+/// Every supported kind of version control, in the order they are checked for in each directory.
+pub static BACKENDS: &[&dyn VcsBackend] = &[&GitBackend, &MercurialBackend, &SubversionBackend];
+
+/// Finds the innermost working copy that the given path is in, with the kind of version control
+/// it is under, using the given backends.
+pub fn detect_vcs(path: &Path, backends: &[&dyn VcsBackend]) -> Option<(PathBuf, VcsKind)> {
+    path.ancestors().find_map(|dir| {
+        let backend = backends.iter().find(|backend| backend.is_root(dir))?;
+        Some((dir.to_path_buf(), backend.kind()))
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A directory for a test's layout, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("vcs-test-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            // Resolves symlinks like macOS' `/tmp`, so that found roots compare equal.
+            Self(path.canonicalize().unwrap())
+        }
+
+        fn dir(&self, relative: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(&path).unwrap();
+            path
+        }
+
+        fn file(&self, relative: &str, content: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn nested_git_repositories_find_the_innermost() {
+        let test = TestDir::new("nested");
+        test.dir("outer/.git");
+        test.dir("outer/inner/.git");
+        let src = test.dir("outer/inner/src");
+
+        assert_eq!(
+            detect_vcs(&src, BACKENDS),
+            Some((test.0.join("outer/inner"), VcsKind::Git)),
+        );
+        assert_eq!(
+            detect_vcs(&test.0.join("outer"), BACKENDS),
+            Some((test.0.join("outer"), VcsKind::Git)),
+        );
+    }
+
+    #[test]
+    fn git_files_pointing_to_a_repository_are_roots() {
+        let test = TestDir::new("gitdir");
+        test.file("worktree/.git", "gitdir: /elsewhere/.git/worktrees/worktree\n");
+        let src = test.dir("worktree/src");
+
+        assert_eq!(
+            detect_vcs(&src, BACKENDS),
+            Some((test.0.join("worktree"), VcsKind::Git)),
+        );
+    }
+
+    #[test]
+    fn other_git_files_are_not_roots() {
+        let test = TestDir::new("not-gitdir");
+        test.file("project/.git", "not a pointer to a repository\n");
+        let project = test.0.join("project");
+
+        assert!(!GitBackend.is_root(&project));
+        assert_eq!(detect_vcs(&project, BACKENDS), None);
+    }
+
+    #[test]
+    fn mercurial_and_subversion_roots_are_found() {
+        let test = TestDir::new("hg-svn");
+        test.dir("hg/.hg");
+        test.dir("svn/.svn");
+
+        assert_eq!(
+            detect_vcs(&test.dir("hg/src"), BACKENDS),
+            Some((test.0.join("hg"), VcsKind::Mercurial)),
+        );
+        assert_eq!(
+            detect_vcs(&test.dir("svn/src"), BACKENDS),
+            Some((test.0.join("svn"), VcsKind::Subversion)),
+        );
+    }
+
+    #[test]
+    fn paths_outside_version_control_have_none() {
+        let test = TestDir::new("none");
+        let src = test.dir("project/src");
+
+        assert_eq!(detect_vcs(&src, BACKENDS), None);
+    }
+}
@@ -0,0 +1,71 @@
+//! Filesystem watching
+
+
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+
+
+
+/// Watches every file under a directory for changes made outside of the editor.
+pub struct FileWatcher {
+    // Watching stops when this is dropped.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl FileWatcher {
+    pub fn new(root: &Path) -> notify::Result<Self> {
+        let (sender, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Takes the changes since this was last called, without blocking.
+    pub fn changes(&self) -> FileChanges {
+        let mut changes = FileChanges::default();
+        let mut seen = HashSet::new();
+        for event in self.events.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(error) => {
+                    eprintln!("ERROR: {error}");
+                    changes.rescan = true;
+                    continue;
+                }
+            };
+            if event.need_rescan() {
+                changes.rescan = true;
+            }
+            // Files being read doesn't change them.
+            if event.kind.is_access() {
+                continue;
+            }
+            for path in event.paths {
+                if seen.insert(path.clone()) {
+                    changes.paths.push(path);
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FileChanges {
+    /// The paths of the files and directories that were created, changed or removed.
+    pub paths: Vec<PathBuf>,
+    /// Whether some changes were missed, so that anything could have changed.
+    pub rescan: bool,
+}
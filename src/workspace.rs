@@ -2,7 +2,16 @@
 
 
 
-use std::{path::{Path, PathBuf}, sync::RwLock};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+};
+
+use ignore::{gitignore::{Gitignore, GitignoreBuilder}, Match};
+use serde::{Deserialize, Serialize};
+
+use crate::vcs::{detect_vcs, VcsKind, BACKENDS};
 
 
 
@@ -10,30 +19,27 @@ use std::{path::{Path, PathBuf}, sync::RwLock};
 pub struct WorkspaceInfo {
     /// The path to this workspace.
     pub path: PathBuf,
-    /// Whether this workspace has version control.
-    pub has_vc: bool,
+    /// The version control that this workspace is under, if any.
+    pub vcs: Option<VcsKind>,
 }
 
+impl WorkspaceInfo {
+    /// Whether this workspace is a git working tree.
+    #[inline]
+    pub fn is_git(&self) -> bool {
+        self.vcs == Some(VcsKind::Git)
+    }
+}
 
 
-/// Finds the current workspace.
-pub fn find_workspace() -> WorkspaceInfo {
-    let mut path = cwd();
-    let mut has_vc = false;
-
-    // Find the first ancestor that has version control, and use it as the workspace.
-    for ancestor in cwd().ancestors() {
-        if ancestor.join(".git").exists()
-            // || ancestor.join(".svn").exists()
-        {
-            path = ancestor.to_path_buf();
-            has_vc = true;
-        }
-    }
 
-    WorkspaceInfo {
-        path,
-        has_vc,
+/// Finds the current workspace, which is the innermost working copy that the current directory
+/// is in, or the current directory if it isn't under version control.
+pub fn find_workspace() -> WorkspaceInfo {
+    let cwd = cwd();
+    match detect_vcs(&cwd, BACKENDS) {
+        Some((path, vcs)) => WorkspaceInfo { path, vcs: Some(vcs) },
+        None => WorkspaceInfo { path: cwd, vcs: None },
     }
 }
 
@@ -62,29 +68,38 @@ pub fn cwd() -> PathBuf {
 
 
 
+/// Reads the top level of the workspace. Directories are read as they are expanded.
 pub fn read_workspace(info: WorkspaceInfo) -> Result<Workspace, std::io::Error> {
-    let entries = read_entries(&info.path, 0, 1)?;
+    let mut ignore_rules = IgnoreRules::new(info.path.clone());
+    let entries = read_entries(&info.path, 0, false, &mut ignore_rules)?;
 
     Ok(Workspace {
         info,
         entries,
+        selected: None,
+        show_ignored: false,
+        ignore_rules,
     })
 }
 
-fn read_entries(path: &Path, level: usize, limit: usize) -> Result<Vec<Entry>, std::io::Error> {
+/// Reads the entries of the directory at the given path, without reading any subdirectories.
+/// Everything in an ignored directory is ignored too.
+fn read_entries(
+    path: &Path,
+    level: usize,
+    dir_ignored: bool,
+    ignore_rules: &mut IgnoreRules,
+) -> Result<Vec<Entry>, std::io::Error> {
     let mut entries = vec![];
     for e in std::fs::read_dir(path)? {
         let entry = e?;
         let path = entry.path();
-        entries.push(if path.is_dir() {
-            let children = if level < limit {
-                read_entries(&path, level + 1, limit)?
-            } else {
-                vec![Entry::ContinuationMarker { parent: path.clone(), level: level + 1 }]
-            };
-            Entry::Dir { path, children, level }
+        let is_dir = path.is_dir();
+        let ignored = dir_ignored || ignore_rules.is_ignored(&path, is_dir);
+        entries.push(if is_dir {
+            Entry::Dir { path, children: None, expanded: false, level, ignored }
         } else {
-            Entry::File { path, level }
+            Entry::File { path, level, ignored }
         });
     }
     entries.sort_by(|a, b| {
@@ -105,51 +120,350 @@ fn read_entries(path: &Path, level: usize, limit: usize) -> Result<Vec<Entry>, s
 pub struct Workspace {
     pub info: WorkspaceInfo,
     pub entries: Vec<Entry>,
+    /// The path of the entry that keyboard navigation moves from, if any.
+    pub selected: Option<PathBuf>,
+    /// Whether ignored and hidden entries are listed.
+    pub show_ignored: bool,
+    ignore_rules: IgnoreRules,
 }
 
 impl Workspace {
-    pub fn entries(&self) -> impl Iterator<Item = EntryView> {
-        self.entries.iter().flat_map(|entry| {
-            if let Entry::Dir { children, .. } = &entry {
-                let mut entries = Vec::with_capacity(children.len() + 1);
-                entries.push(EntryView {
+    /// Iterates over the visible entries, in the order they are listed. The entries of expanded
+    /// directories follow them, however deeply they are nested. Ignored entries are only visible
+    /// while they are shown.
+    pub fn entries(&self) -> impl Iterator<Item = EntryView<'_>> {
+        fn push_visible<'a>(
+            entries: &'a [Entry],
+            show_ignored: bool,
+            views: &mut Vec<EntryView<'a>>,
+        ) {
+            for entry in entries {
+                if entry.is_ignored() && !show_ignored {
+                    continue;
+                }
+                views.push(EntryView {
                     name: entry.name(),
                     path: entry.path(),
                     level: entry.level(),
+                    is_dir: entry.is_dir(),
+                    expanded: entry.is_expanded(),
+                    ignored: entry.is_ignored(),
                 });
-                entries.extend(children.iter().flat_map(|entry| {
-                    if let Entry::Dir { children, .. } = &entry {
-                        let mut entries = Vec::with_capacity(children.len() + 1);
-                        entries.push(EntryView {
-                            name: entry.name(),
-                            path: entry.path(),
-                            level: entry.level(),
-                        });
-                        entries.extend(children.iter().map(|e| EntryView {
-                            name: e.name(),
-                            path: e.path(),
-                            level: e.level(),
-                        }));
-
-                        entries.into_iter()
-                    } else {
-                        vec![EntryView {
-                            name: entry.name(),
-                            path: entry.path(),
-                            level: entry.level(),
-                        }].into_iter()
+                if let Entry::Dir { children: Some(children), expanded: true, .. } = entry {
+                    push_visible(children, show_ignored, views);
+                }
+            }
+        }
+
+        let mut views = vec![];
+        push_visible(&self.entries, self.show_ignored, &mut views);
+
+        views.into_iter()
+    }
+
+    /// Reads the directory at the given path again, if it has been read before. The entries that
+    /// are still there keep their state, such as whether they are expanded. Its ignore files are
+    /// read again too, in case they are what changed.
+    pub fn refresh_dir(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        self.ignore_rules.forget(path);
+        let Self { info, entries, ignore_rules, .. } = self;
+        let (entries, level, dir_ignored) = if path == info.path {
+            (entries, 0, false)
+        } else {
+            match find_entry_mut(entries, ignore_rules, path, false) {
+                Some(Entry::Dir { children: Some(children), level, ignored, .. }) => {
+                    let (level, ignored) = (*level + 1, *ignored);
+                    (children, level, ignored)
+                }
+                _ => return Ok(()),
+            }
+        };
+
+        let mut new_entries = match read_entries(path, level, dir_ignored, ignore_rules) {
+            Ok(new_entries) => new_entries,
+            // The directory's parent will be refreshed too, which removes it.
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        for new_entry in &mut new_entries {
+            let old_index = entries.iter().position(|entry| {
+                entry.path() == new_entry.path() && entry.is_dir() == new_entry.is_dir()
+            });
+            if let Some(old_index) = old_index {
+                let ignored = new_entry.is_ignored();
+                *new_entry = entries.swap_remove(old_index);
+                // The rules for what is inside it may have changed as well.
+                update_ignored(new_entry, ignored, ignore_rules);
+            }
+        }
+        *entries = new_entries;
+
+        Ok(())
+    }
+
+    /// Reads every directory that has been read before again, for when it isn't known what has
+    /// changed.
+    pub fn refresh_all(&mut self) -> Result<(), std::io::Error> {
+        fn push_read(entries: &[Entry], paths: &mut Vec<PathBuf>) {
+            for entry in entries {
+                if let Entry::Dir { path, children: Some(children), .. } = entry {
+                    paths.push(path.clone());
+                    push_read(children, paths);
+                }
+            }
+        }
+
+        let mut paths = vec![self.info.path.clone()];
+        push_read(&self.entries, &mut paths);
+        // Parents come before their children, so directories that are gone are skipped.
+        for path in paths {
+            self.refresh_dir(&path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Expands the directory at the given path, reading its entries if they haven't been read
+    /// yet. Returns whether there was a directory to expand.
+    pub fn expand(&mut self, path: &Path) -> Result<bool, std::io::Error> {
+        let Self { entries, ignore_rules, .. } = self;
+        let Some(Entry::Dir { path, children, expanded, level, ignored }) =
+            find_entry_mut(entries, ignore_rules, path, true)
+        else {
+            return Ok(false);
+        };
+        if children.is_none() {
+            *children = Some(read_entries(path, *level + 1, *ignored, ignore_rules)?);
+        }
+        *expanded = true;
+
+        Ok(true)
+    }
+
+    /// Collapses the directory at the given path. The directories inside it stay expanded, and
+    /// are shown again when it is next expanded. Returns whether it was expanded.
+    pub fn collapse(&mut self, path: &Path) -> bool {
+        match find_entry_mut(&mut self.entries, &mut self.ignore_rules, path, false) {
+            Some(Entry::Dir { expanded, .. }) => std::mem::replace(expanded, false),
+            _ => false,
+        }
+    }
+
+    /// Expands the directory at the given path if it is collapsed, or collapses it otherwise.
+    pub fn toggle(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        if !self.collapse(path) {
+            self.expand(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// The paths of every expanded directory, including those inside collapsed directories.
+    pub fn expanded_paths(&self) -> Vec<PathBuf> {
+        fn push_expanded(entries: &[Entry], paths: &mut Vec<PathBuf>) {
+            for entry in entries {
+                if let Entry::Dir { path, children, expanded, .. } = entry {
+                    if *expanded {
+                        paths.push(path.clone());
+                    }
+                    if let Some(children) = children {
+                        push_expanded(children, paths);
                     }
-                }));
+                }
+            }
+        }
 
-                entries.into_iter()
-            } else {
-                vec![EntryView {
-                    name: entry.name(),
-                    path: entry.path(),
-                    level: entry.level(),
-                }].into_iter()
+        let mut paths = vec![];
+        push_expanded(&self.entries, &mut paths);
+
+        paths
+    }
+
+    /// Expands every directory at the given paths. Paths that are no longer directories in this
+    /// workspace are skipped.
+    pub fn restore_expanded(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            let _ = self.expand(path);
+        }
+    }
+}
+
+impl Workspace {
+    /// Gets the path in this workspace for a path typed relative to it. Paths that are empty,
+    /// absolute, or that go up out of the workspace are invalid.
+    pub fn path_for(&self, name: &str) -> Result<PathBuf, FileOpError> {
+        let relative = Path::new(name.trim());
+        let valid = relative.components().next().is_some()
+            && relative.components().all(|component| matches!(component, Component::Normal(_)));
+        if !valid {
+            return Err(FileOpError::InvalidName(name.to_string()));
+        }
+
+        Ok(self.info.path.join(relative))
+    }
+
+    /// Gets the given path relative to this workspace, as it would be typed for
+    /// [`Workspace::path_for`].
+    pub fn relative_name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.info.path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Creates an empty file at the given path, along with any directories above it.
+    pub fn create_file(&mut self, path: &Path) -> Result<(), FileOpError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| FileOpError::io(parent, error))?;
+        }
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|error| FileOpError::io(path, error))?;
+        self.refresh_ancestors(path);
+
+        Ok(())
+    }
+
+    /// Creates a directory at the given path, along with any directories above it.
+    pub fn create_dir(&mut self, path: &Path) -> Result<(), FileOpError> {
+        if path.exists() {
+            return Err(FileOpError::AlreadyExists(path.to_path_buf()));
+        }
+        std::fs::create_dir_all(path).map_err(|error| FileOpError::io(path, error))?;
+        self.refresh_ancestors(path);
+
+        Ok(())
+    }
+
+    /// Renames or moves the entry at `from` to `to`, creating any directories above `to`. A
+    /// directory's expanded directories stay expanded.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileOpError> {
+        if to.exists() {
+            return Err(FileOpError::AlreadyExists(to.to_path_buf()));
+        }
+        if to.starts_with(from) {
+            return Err(FileOpError::InvalidName(to.display().to_string()));
+        }
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| FileOpError::io(parent, error))?;
+        }
+        std::fs::rename(from, to).map_err(|error| FileOpError::io(from, error))?;
+
+        let expanded: Vec<PathBuf> = self.expanded_paths().iter()
+            .filter_map(|path| Some(to.join(path.strip_prefix(from).ok()?)))
+            .collect();
+        self.refresh_ancestors(from);
+        self.refresh_ancestors(to);
+        self.restore_expanded(&expanded);
+        if self.selected.as_deref().is_some_and(|selected| selected.starts_with(from)) {
+            self.selected = Some(to.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    /// Moves the entry at the given path to the trash, where it can be recovered from. The entry
+    /// after it is selected in its place.
+    pub fn delete(&mut self, path: &Path) -> Result<(), FileOpError> {
+        trash::delete(path).map_err(|error| FileOpError::Trash {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let selected_index = self.selected_index();
+        self.refresh_ancestors(path);
+        if self.selected.as_deref().is_some_and(|selected| selected.starts_with(path)) {
+            self.selected = None;
+            if let Some(index) = selected_index {
+                self.select_index(index);
             }
-        })
+        }
+
+        Ok(())
+    }
+
+    /// Reads the directories above the given path again. Errors are ignored, since the watcher
+    /// will catch up with any changes that are missed.
+    pub fn refresh_ancestors(&mut self, path: &Path) {
+        let Ok(relative) = path.strip_prefix(&self.info.path) else { return; };
+        let mut dir = self.info.path.clone();
+        let _ = self.refresh_dir(&dir);
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            let _ = self.refresh_dir(&dir);
+        }
+    }
+
+    /// Expands the directories above the given path, so that its entry is visible. Directories
+    /// that can't be read are skipped.
+    pub fn reveal(&mut self, path: &Path) {
+        let Ok(relative) = path.strip_prefix(&self.info.path) else { return; };
+        let mut dir = self.info.path.clone();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            let _ = self.expand(&dir);
+        }
+    }
+}
+
+impl Workspace {
+    /// The index of the selected entry among the visible entries, if it is visible.
+    pub fn selected_index(&self) -> Option<usize> {
+        let selected = self.selected.as_deref()?;
+        self.entries().position(|entry| entry.path == selected)
+    }
+
+    /// Selects the visible entry at the given index, clamped to the visible entries.
+    pub fn select_index(&mut self, index: usize) {
+        let count = self.entries().count();
+        if count > 0 {
+            let selected = self.entries()
+                .nth(index.min(count - 1))
+                .map(|entry| entry.path.to_path_buf());
+            self.selected = selected;
+        }
+    }
+
+    /// Moves the selection by the given number of visible entries. Selects the first entry if
+    /// nothing visible is selected.
+    pub fn move_selection(&mut self, offset: isize) {
+        match self.selected_index() {
+            Some(index) => self.select_index(index.saturating_add_signed(offset)),
+            None => self.select_index(0),
+        }
+    }
+
+    /// Selects the directory that contains the selected entry, unless it is the workspace itself.
+    pub fn select_parent(&mut self) {
+        let Some(parent) = self.selected.as_deref().and_then(Path::parent) else { return; };
+        if parent != self.info.path {
+            self.selected = Some(parent.to_path_buf());
+        }
+    }
+}
+
+/// Finds the entry at the given path. With `read_dirs`, the directories above it are read if they
+/// haven't been yet. Otherwise, entries in unread directories aren't found.
+fn find_entry_mut<'a>(
+    mut entries: &'a mut Vec<Entry>,
+    ignore_rules: &mut IgnoreRules,
+    path: &Path,
+    read_dirs: bool,
+) -> Option<&'a mut Entry> {
+    loop {
+        let entry = entries.iter_mut().find(|entry| path.starts_with(entry.path()))?;
+        if entry.path() == path {
+            return Some(entry);
+        }
+        let Entry::Dir { path: dir_path, children, level, ignored, .. } = entry else {
+            return None;
+        };
+        if children.is_none() && read_dirs {
+            *children = Some(read_entries(dir_path, *level + 1, *ignored, ignore_rules).ok()?);
+        }
+        entries = children.as_mut()?;
     }
 }
 
@@ -158,15 +472,18 @@ pub enum Entry {
     File {
         path: PathBuf,
         level: usize,
+        /// Whether this file is ignored by an ignore file, hidden, or in an ignored directory.
+        ignored: bool,
     },
     Dir {
         path: PathBuf,
-        children: Vec<Entry>,
-        level: usize,
-    },
-    ContinuationMarker {
-        parent: PathBuf,
+        /// The entries of this directory, or `None` if it hasn't been read yet.
+        children: Option<Vec<Entry>>,
+        expanded: bool,
         level: usize,
+        /// Whether this directory is ignored by an ignore file, hidden, or in an ignored
+        /// directory.
+        ignored: bool,
     },
 }
 
@@ -175,28 +492,88 @@ impl Entry {
         matches!(self, Entry::Dir { .. })
     }
 
+    pub fn is_expanded(&self) -> bool {
+        matches!(self, Entry::Dir { expanded: true, .. })
+    }
+
+    pub fn is_ignored(&self) -> bool {
+        match self {
+            Entry::File { ignored, .. } => *ignored,
+            Entry::Dir { ignored, .. } => *ignored,
+        }
+    }
+
     pub fn path(&self) -> &Path {
         match self {
             Entry::File { path, .. } => &path,
             Entry::Dir { path, .. } => &path,
-            Entry::ContinuationMarker { parent, .. } => &parent,
         }
     }
 
     pub fn name(&self) -> &str {
-        match self {
-            Entry::ContinuationMarker { .. } => "...",
-            other => {
-                other.path().file_name().and_then(|os_str| os_str.to_str()).unwrap()
-            }
-        }
+        self.path().file_name().and_then(|os_str| os_str.to_str()).unwrap()
     }
 
     pub fn level(&self) -> usize {
         match self {
             Entry::File { level, .. } => *level,
             Entry::Dir { level, .. } => *level,
-            Entry::ContinuationMarker { level, .. } => *level,
+        }
+    }
+}
+
+/// Why creating, renaming or deleting an entry failed.
+#[derive(Debug)]
+pub enum FileOpError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// Something is already at the path that an entry would be created or moved to.
+    AlreadyExists(PathBuf),
+    /// The path given for an entry isn't one that it can have.
+    InvalidName(String),
+    Trash {
+        path: PathBuf,
+        error: trash::Error,
+    },
+}
+
+impl FileOpError {
+    fn io(path: &Path, error: std::io::Error) -> Self {
+        if error.kind() == std::io::ErrorKind::AlreadyExists {
+            FileOpError::AlreadyExists(path.to_path_buf())
+        } else {
+            FileOpError::Io { path: path.to_path_buf(), error }
+        }
+    }
+}
+
+impl std::fmt::Display for FileOpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileOpError::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            FileOpError::AlreadyExists(path) => {
+                write!(f, "{}: already exists", path.display())
+            }
+            FileOpError::InvalidName(name) => {
+                write!(f, "invalid path: {name:?}")
+            }
+            FileOpError::Trash { path, error } => {
+                write!(f, "{}: couldn't move to trash: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileOpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileOpError::Io { error, .. } => Some(error),
+            FileOpError::Trash { error, .. } => Some(error),
+            FileOpError::AlreadyExists(_) | FileOpError::InvalidName(_) => None,
         }
     }
 }
@@ -205,4 +582,155 @@ pub struct EntryView<'a> {
     pub name: &'a str,
     pub path: &'a Path,
     pub level: usize,
+    pub is_dir: bool,
+    pub expanded: bool,
+    pub ignored: bool,
+}
+
+
+
+/// The ignore rules of each directory in a workspace, read from its `.gitignore` and `.ignore`
+/// files as they are needed.
+struct IgnoreRules {
+    root: PathBuf,
+    /// The rules of each directory that has been checked, or `None` if it has no ignore files.
+    dirs: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl IgnoreRules {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            dirs: HashMap::new(),
+        }
+    }
+
+    /// Whether the entry at the given path is hidden, or ignored by the rules of the directories
+    /// above it. The rules of deeper directories take precedence, so a negated pattern can
+    /// include an entry that a parent directory ignores.
+    fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name.as_encoded_bytes().starts_with(b".")) {
+            return true;
+        }
+
+        let mut dir = path.parent();
+        while let Some(current) = dir.filter(|dir| dir.starts_with(&self.root)) {
+            match self.rules(current).map(|rules| rules.matched(path, is_dir)) {
+                Some(Match::Ignore(_)) => return true,
+                Some(Match::Whitelist(_)) => return false,
+                _ => {}
+            }
+            dir = current.parent();
+        }
+
+        false
+    }
+
+    fn rules(&mut self, dir: &Path) -> Option<&Gitignore> {
+        if !self.dirs.contains_key(dir) {
+            let rules = read_ignore_rules(dir, dir == self.root);
+            self.dirs.insert(dir.to_path_buf(), rules);
+        }
+
+        self.dirs.get(dir)?.as_ref()
+    }
+
+    /// Forgets the rules of the given directory, so that they are read again when next needed.
+    fn forget(&mut self, dir: &Path) {
+        self.dirs.remove(dir);
+    }
+}
+
+/// Reads the ignore files in the given directory. `.ignore` files take precedence over
+/// `.gitignore` files, and the workspace's git excludes come last.
+fn read_ignore_rules(dir: &Path, is_root: bool) -> Option<Gitignore> {
+    let mut files = vec![];
+    if is_root {
+        files.push(dir.join(".git/info/exclude"));
+    }
+    files.push(dir.join(".gitignore"));
+    files.push(dir.join(".ignore"));
+    files.retain(|file| file.is_file());
+    if files.is_empty() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    for file in &files {
+        // Lines that fail to parse are skipped, and the rest still apply.
+        if let Some(error) = builder.add(file) {
+            eprintln!("ERROR: {error}");
+        }
+    }
+
+    builder.build()
+        .inspect_err(|error| eprintln!("ERROR: {error}"))
+        .ok()
+}
+
+/// Sets whether the given entry is ignored, and checks the entries inside it again.
+fn update_ignored(entry: &mut Entry, dir_ignored: bool, ignore_rules: &mut IgnoreRules) {
+    match entry {
+        Entry::File { ignored, .. } => *ignored = dir_ignored,
+        Entry::Dir { children, ignored, .. } => {
+            *ignored = dir_ignored;
+            for child in children.iter_mut().flatten() {
+                let child_ignored = dir_ignored
+                    || ignore_rules.is_ignored(child.path(), child.is_dir());
+                update_ignored(child, child_ignored, ignore_rules);
+            }
+        }
+    }
+}
+
+
+
+/// The expanded directories of every workspace, remembered between sessions.
+#[derive(Default, Deserialize, Serialize)]
+struct TreeState {
+    /// The paths of the expanded directories relative to each workspace, by workspace path.
+    #[serde(default)]
+    expanded: BTreeMap<String, Vec<String>>,
+}
+
+impl TreeState {
+    fn path() -> Option<PathBuf> {
+        crate::config_dir().map(|dir| dir.join("tree_state.toml"))
+    }
+
+    fn load() -> Self {
+        Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Loads the paths of the directories that were expanded when the workspace was last open.
+pub fn load_expanded(info: &WorkspaceInfo) -> Vec<PathBuf> {
+    let mut state = TreeState::load();
+    let paths = state.expanded.remove(&info.path.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut paths: Vec<PathBuf> = paths.iter().map(|path| info.path.join(path)).collect();
+    // Parents are expanded before their children.
+    paths.sort_by_key(|path| path.components().count());
+
+    paths
+}
+
+/// Remembers which of the workspace's directories are expanded, for the next time it is opened.
+pub fn save_expanded(workspace: &Workspace) -> Result<(), std::io::Error> {
+    let Some(path) = TreeState::path() else { return Ok(()); };
+    let mut state = TreeState::load();
+    let expanded = workspace.expanded_paths().iter()
+        .filter_map(|path| path.strip_prefix(&workspace.info.path).ok())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    state.expanded.insert(workspace.info.path.to_string_lossy().into_owned(), expanded);
+
+    let content = toml::to_string(&state)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::write_atomic(&path, content.as_bytes())
 }